@@ -1,5 +1,7 @@
-use criterion::{criterion_group, criterion_main, Criterion};
-use xmp::OptionalResults;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use xmp::{ImageType, OptionalResults};
 pub fn read_xmp() {
     OptionalResults::load("assets/file.xmp").unwrap();
 }
@@ -8,9 +10,58 @@ pub fn read_jpeg() {
     OptionalResults::load("assets/1.jpg").unwrap();
 }
 
+/// Writes a jpeg with an xmp segment followed by `size` bytes of entropy coded data
+fn jpeg_with_size(size: usize) -> PathBuf {
+    let xml = std::fs::read("assets/3.xmp").unwrap();
+    let path = std::env::temp_dir().join(format!("xmp_read_{}.jpg", size));
+    let mut file = std::io::BufWriter::new(std::fs::File::create(&path).unwrap());
+    let signature = b"http://ns.adobe.com/xap/1.0/\0";
+    file.write_all(&[0xff, 0xd8, 0xff, 0xe1]).unwrap();
+    file.write_all(&((signature.len() + xml.len() + 2) as u16).to_be_bytes())
+        .unwrap();
+    file.write_all(signature).unwrap();
+    file.write_all(&xml).unwrap();
+    file.write_all(&[0xff, 0xda, 0, 8, 1, 1, 0, 0, 0x3f, 0])
+        .unwrap();
+    file.write_all(&vec![0x55; size]).unwrap();
+    file.write_all(&[0xff, 0xd9]).unwrap();
+    file.flush().unwrap();
+    path
+}
+
+pub fn read_jpeg_header(path: &Path) {
+    OptionalResults::load(path).unwrap();
+}
+
+/// Reads the whole file into memory before parsing the same header, the I/O loading used to
+/// do. The cost of the full segment parse it also did isn't included
+pub fn read_jpeg_whole_file(path: &Path) {
+    let data = std::fs::read(path).unwrap();
+    OptionalResults::from_bytes(ImageType::Jpg, &data).unwrap();
+}
+
 fn read_benchmark(c: &mut Criterion) {
+    // Loading only reads the header so it shouldn't grow with the size of the image, unlike
+    // reading the whole file
+    let mut group = c.benchmark_group("read_jpg_size");
+    for size in [64 << 10, 4 << 20, 32 << 20] {
+        let path = jpeg_with_size(size);
+        group.bench_with_input(BenchmarkId::new("load", size), &path, |b, path| {
+            b.iter(|| read_jpeg_header(path))
+        });
+        group.bench_with_input(
+            BenchmarkId::new("read_whole_file", size),
+            &path,
+            |b, path| b.iter(|| read_jpeg_whole_file(path)),
+        );
+    }
+    group.finish();
+
     c.bench_function("read_xmp", |b| b.iter(read_xmp));
-    c.bench_function("read_jpg", |b| b.iter(read_jpeg));
+    // The sample image isn't part of the repository
+    if Path::new("assets/1.jpg").exists() {
+        c.bench_function("read_jpg", |b| b.iter(read_jpeg));
+    }
 }
 
 criterion_group!(benches, read_benchmark);
@@ -268,6 +268,11 @@ impl Element {
         }
     }
 
+    /// Removes an attribute, returning its value if it existed.
+    pub fn remove_attr(&mut self, name: &str) -> Option<String> {
+        self.attributes.remove(name)
+    }
+
     /// Returns whether the element has the given name and namespace.
     ///
     /// # Examples
@@ -0,0 +1,74 @@
+#[cfg(test)]
+use crate::isobmff::sample_isobmff;
+use crate::isobmff::{__isobmff_write_xml, __isobmff_xml};
+use crate::*;
+use std::io::Cursor;
+
+impl UpdateResults {
+    pub fn update_avif(
+        &self,
+        path: impl AsRef<Path>,
+        options: UpdateOptions,
+    ) -> Result<(), XmpError> {
+        let mut reader = BufReader::new(std::fs::File::open(&path)?);
+        replace_file(&path, |writer| {
+            self.apply_avif(&mut reader, writer, options)
+        })
+    }
+
+    pub fn apply_avif<R: Read, W: Write>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+        options: UpdateOptions,
+    ) -> Result<(), XmpError> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        let xml = __isobmff_xml(&data).unwrap_or_else(|_e| DEFAULT_XML.as_bytes().to_vec());
+        let xml = self.update_xml(Cursor::new(xml), options)?;
+        __isobmff_write_xml(&data, &xml, writer)
+    }
+}
+
+impl OptionalResults {
+    pub fn load_avif(path: impl AsRef<Path>) -> Result<Self, XmpError> {
+        Self::read_avif(&mut BufReader::new(std::fs::File::open(path)?))
+    }
+
+    pub fn read_avif<R: Read>(reader: &mut R) -> Result<Self, XmpError> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        Self::from_reader(Cursor::new(__isobmff_xml(&data)?))
+    }
+}
+
+#[test]
+pub fn avif_update() {
+    use crate::isobmff::{find, Meta};
+
+    let dir = std::env::temp_dir().join("xmp_avif_update");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("file.avif");
+    let original = sample_isobmff(b"avif", b"av01", b"av1C");
+    std::fs::write(&path, &original).unwrap();
+
+    let u = UpdateResults {
+        stars: Some(5),
+        colors: Some(String::from("Purple")),
+        ..Default::default()
+    };
+    u.update(&path).unwrap();
+    let r = OptionalResults::load(&path).unwrap();
+    assert_eq!(r.stars, Some(5));
+    assert_eq!(r.colors.as_deref(), Some("Purple"));
+
+    // The av1C and ispe properties and their associations are untouched
+    let data = std::fs::read(&path).unwrap();
+    let meta = Meta::parse(&data).unwrap();
+    let original_meta = Meta::parse(&original).unwrap();
+    assert_eq!(
+        find(&meta.children, b"iprp").unwrap().data,
+        find(&original_meta.children, b"iprp").unwrap().data
+    );
+    assert_eq!(meta.item_data(&data, 1).unwrap(), b"image data");
+}
@@ -0,0 +1,241 @@
+//! Runtime registry of the formats [`UpdateResults::update`] and [`OptionalResults::load`]
+//! dispatch to, which other crates can extend with their own formats
+//!
+//! [`UpdateResults::apply_to`] and [`OptionalResults::read_from`] consult it as well, detecting
+//! the format from the content alone.
+use crate::*;
+use std::io::Cursor;
+use std::sync::{Arc, OnceLock, PoisonError, RwLock};
+
+/// Object safe stand in for `Read + Seek`, implemented for every reader which is both
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek + ?Sized> ReadSeek for T {}
+
+/// A file format holding an xmp packet
+///
+/// Only [`XmpContainer::extract`] and [`XmpContainer::replace`] have to know about the format,
+/// loading and updating files is built on top of them. Updates are written to a temporary file
+/// which replaces the original once the whole file was written.
+pub trait XmpContainer: Send + Sync {
+    /// Name of the format, used in error messages
+    fn name(&self) -> &str;
+
+    /// Whether the file at `path` is in this format, `magic` holds the first bytes of the file
+    /// and is empty when it couldn't be read
+    fn detect(&self, path: &Path, magic: &[u8]) -> bool;
+
+    /// Reads the packet, failing with [`XmpErrorKind::XMPMissing`] when the file doesn't hold one
+    fn extract(&self, reader: &mut dyn ReadSeek) -> Result<Vec<u8>, XmpError>;
+
+    /// Copies the file in `reader` to `writer` with its packet replaced by `xml`, adding one if
+    /// the file doesn't hold a packet yet
+    fn replace(
+        &self,
+        reader: &mut dyn ReadSeek,
+        writer: &mut dyn Write,
+        xml: &[u8],
+        options: &UpdateOptions,
+    ) -> Result<(), XmpError>;
+
+    /// Loads the file in `reader`, which [`OptionalResults::read_from`] uses
+    fn read(&self, reader: &mut dyn ReadSeek) -> Result<OptionalResults, XmpError> {
+        OptionalResults::from_reader(Cursor::new(self.extract(reader)?))
+    }
+
+    /// Copies the file in `reader` to `writer` with the update applied, which
+    /// [`UpdateResults::apply_to`] uses
+    fn apply(
+        &self,
+        results: &UpdateResults,
+        reader: &mut dyn ReadSeek,
+        writer: &mut dyn Write,
+        options: UpdateOptions,
+    ) -> Result<(), XmpError> {
+        let xml = self
+            .extract(reader)
+            .unwrap_or_else(|_e| DEFAULT_XML.as_bytes().to_vec());
+        let xml = results.update_xml(Cursor::new(xml), options.clone())?;
+        reader.seek(SeekFrom::Start(0))?;
+        self.replace(reader, writer, &xml, &options)
+    }
+
+    fn load(&self, path: &Path) -> Result<OptionalResults, XmpError> {
+        self.read(&mut BufReader::new(std::fs::File::open(path)?))
+    }
+
+    fn update(
+        &self,
+        results: &UpdateResults,
+        path: &Path,
+        options: UpdateOptions,
+    ) -> Result<(), XmpError> {
+        let mut reader = BufReader::new(std::fs::File::open(path)?);
+        replace_file(path, |writer| {
+            self.apply(results, &mut reader, writer, options)
+        })
+    }
+}
+
+/// Containers tried in turn, the ones registered last first so they can take over files a
+/// built-in container would otherwise handle
+#[derive(Default, Clone)]
+pub struct ContainerRegistry {
+    containers: Vec<Arc<dyn XmpContainer>>,
+}
+
+impl ContainerRegistry {
+    /// A registry without any containers
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A registry holding the jpeg, png, raw and xmp sidecar containers, as far as their features
+    /// are enabled
+    pub fn with_builtins() -> Self {
+        let mut registry = Self::new();
+        registry.register(crate::raw::RawContainer);
+        registry.register(crate::xml::SidecarContainer);
+        #[cfg(feature = "jpeg")]
+        registry.register(crate::jpg::JpegContainer);
+        #[cfg(feature = "png")]
+        registry.register(crate::png::PngContainer);
+        registry
+    }
+
+    pub fn register(&mut self, container: impl XmpContainer + 'static) {
+        self.containers.push(Arc::new(container));
+    }
+
+    /// The container handling the file at `path` which starts with `magic`
+    pub fn find(&self, path: &Path, magic: &[u8]) -> Option<Arc<dyn XmpContainer>> {
+        self.containers
+            .iter()
+            .rev()
+            .find(|container| container.detect(path, magic))
+            .cloned()
+    }
+}
+
+fn global() -> &'static RwLock<ContainerRegistry> {
+    static REGISTRY: OnceLock<RwLock<ContainerRegistry>> = OnceLock::new();
+    REGISTRY.get_or_init(|| RwLock::new(ContainerRegistry::with_builtins()))
+}
+
+/// Registers a container with the registry used by [`UpdateResults::update`] and
+/// [`OptionalResults::load`], taking precedence over the containers registered before it
+pub fn register(container: impl XmpContainer + 'static) {
+    global()
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .register(container);
+}
+
+/// Looks the file up in the global registry, the lock is released before the container runs
+pub(crate) fn find(path: &Path, magic: &[u8]) -> Option<Arc<dyn XmpContainer>> {
+    global()
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .find(path, magic)
+}
+
+/// A made up format, the signature followed by the length of the packet and the packet, then
+/// the rest of the file
+#[cfg(test)]
+struct Proof;
+
+#[cfg(test)]
+impl XmpContainer for Proof {
+    fn name(&self) -> &str {
+        "proof"
+    }
+
+    fn detect(&self, _path: &Path, magic: &[u8]) -> bool {
+        magic.starts_with(b"PRF1")
+    }
+
+    fn extract(&self, reader: &mut dyn ReadSeek) -> Result<Vec<u8>, XmpError> {
+        let mut header = [0; 8];
+        reader.read_exact(&mut header)?;
+        let len = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+        if len == 0 {
+            return Err(XmpErrorKind::XMPMissing.into());
+        }
+        let mut xml = vec![0; len as usize];
+        reader.read_exact(&mut xml)?;
+        Ok(xml)
+    }
+
+    fn replace(
+        &self,
+        reader: &mut dyn ReadSeek,
+        writer: &mut dyn Write,
+        xml: &[u8],
+        _options: &UpdateOptions,
+    ) -> Result<(), XmpError> {
+        let mut header = [0; 8];
+        reader.read_exact(&mut header)?;
+        let len = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+        reader.seek(SeekFrom::Current(len.into()))?;
+        writer.write_all(b"PRF1")?;
+        writer.write_all(&(xml.len() as u32).to_be_bytes())?;
+        writer.write_all(xml)?;
+        std::io::copy(reader, writer)?;
+        Ok(())
+    }
+}
+
+#[test]
+pub fn container_builtins() {
+    let registry = ContainerRegistry::with_builtins();
+    let find = |path: &str, magic: &[u8]| {
+        registry
+            .find(Path::new(path), magic)
+            .map(|c| c.name().to_owned())
+    };
+    #[cfg(feature = "jpeg")]
+    assert_eq!(find("a.jpg", b"\xff\xd8\xff\xe0").as_deref(), Some("jpeg"));
+    #[cfg(feature = "png")]
+    assert_eq!(find("a.bin", b"\x89PNG\r\n\x1a\n").as_deref(), Some("png"));
+    assert_eq!(find("a.nef", b"MM\0*").as_deref(), Some("raw"));
+    assert_eq!(find("a.xmp", b"").as_deref(), Some("xmp"));
+    assert_eq!(find("a.eps", b"%!PS-Adobe-3.0"), None);
+    assert!(ContainerRegistry::new()
+        .find(Path::new("a.xmp"), b"")
+        .is_none());
+}
+
+#[test]
+pub fn container_register() {
+    let dir = std::env::temp_dir().join("xmp_container_register");
+    std::fs::create_dir_all(&dir).unwrap();
+    // The extension of a format which is handled by the packet scanner otherwise
+    let path = dir.join("file.eps");
+    std::fs::write(&path, b"PRF1\0\0\0\0image data").unwrap();
+
+    register(Proof);
+    let u = UpdateResults {
+        stars: Some(3),
+        colors: Some(String::from("Purple")),
+        ..Default::default()
+    };
+    u.update(&path).unwrap();
+    let r = OptionalResults::load(&path).unwrap();
+    assert_eq!(r.stars, Some(3));
+    assert_eq!(r.colors.as_deref(), Some("Purple"));
+    assert!(std::fs::read(&path).unwrap().ends_with(b"image data"));
+
+    // The in-memory api goes by the content
+    let data = std::fs::read(&path).unwrap();
+    let r = OptionalResults::from_bytes(ImageType::Others, &data).unwrap();
+    assert_eq!(r.stars, Some(3));
+    let u = UpdateResults {
+        stars: Some(5),
+        ..Default::default()
+    };
+    let data = u.apply_to_bytes(ImageType::Others, &data).unwrap();
+    assert!(data.starts_with(b"PRF1") && data.ends_with(b"image data"));
+    let r = OptionalResults::from_bytes(ImageType::Others, &data).unwrap();
+    assert_eq!(r.stars, Some(5));
+    assert_eq!(r.colors.as_deref(), Some("Purple"));
+}
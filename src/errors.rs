@@ -39,15 +39,57 @@ pub enum XmpErrorKind {
     #[error("Couldn't find xmp metadata in JFIF header")]
     JFIFHeaderMissing,
     #[cfg(feature = "jpeg")]
-    #[error("{0}")]
-    JfifError(#[from] jfifdump::JfifError),
+    #[error("Malformed JPEG structure")]
+    InvalidJpeg,
     #[cfg(feature = "jpeg")]
+    #[error("Xmp packet doesn't fit in a jpeg even when split")]
+    PacketTooLarge,
+    #[cfg(feature = "png")]
     #[error("{0}")]
     ImgParts(#[from] img_parts::Error),
-    #[cfg(feature = "jpeg")]
+    #[cfg(any(feature = "jpeg", feature = "png"))]
     #[error("{0}")]
     ExifError(#[from] exif::Error),
 
+    #[cfg(feature = "png")]
+    #[error("Malformed xmp iTXt chunk")]
+    InvalidITxt,
+
+    #[error("Malformed or unsupported RDF structure")]
+    InvalidRdf,
+
+    #[error("Invalid namespace or prefix")]
+    InvalidNamespace,
+
+    #[error("Malformed xmp packet wrapper")]
+    InvalidPacket,
+    #[error("Xmp packet is read-only")]
+    ReadOnlyPacket,
+    #[error("Xmp packet doesn't fit in the padding of the existing one")]
+    PacketDoesNotFit,
+
+    #[error("Malformed TIFF structure")]
+    InvalidTiff,
+
+    #[error("Malformed or unsupported ISOBMFF structure")]
+    InvalidIsobmff,
+
+    #[cfg(feature = "webp")]
+    #[error("Malformed WebP structure")]
+    InvalidWebP,
+
+    #[cfg(feature = "gif")]
+    #[error("Malformed GIF structure")]
+    InvalidGif,
+
+    #[cfg(feature = "psd")]
+    #[error("Malformed PSD structure")]
+    InvalidPsd,
+
+    #[cfg(feature = "jxl")]
+    #[error("Malformed JPEG XL structure")]
+    InvalidJxl,
+
     #[cfg(feature = "raw")]
     #[error("{0}")]
     LibrawError(#[from] libraw_r::LibrawError),
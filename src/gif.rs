@@ -0,0 +1,264 @@
+use crate::*;
+use std::io::Cursor;
+
+const EXTENSION: u8 = 0x21;
+const IMAGE_DESCRIPTOR: u8 = 0x2c;
+const TRAILER: u8 = 0x3b;
+const APPLICATION: u8 = 0xff;
+const GRAPHIC_CONTROL: u8 = 0xf9;
+
+/// Application identifier and authentication code of the xmp block as per the XMP spec part 3
+const XMP_APPLICATION: &[u8; 11] = b"XMP DataXMP";
+/// Length of the magic trailer following the packet, including the block terminator
+const MAGIC_TRAILER_LEN: usize = 258;
+
+/// Bytes 0x01, 0xff, 0xfe, ..., 0x00 and the block terminator which let readers unaware of
+/// the xmp block skip the packet as if it were sub-blocks
+fn magic_trailer() -> [u8; MAGIC_TRAILER_LEN] {
+    let mut trailer = [0; MAGIC_TRAILER_LEN];
+    trailer[0] = 0x01;
+    for (byte, value) in trailer[1..257].iter_mut().zip((0..=0xff).rev()) {
+        *byte = value;
+    }
+    trailer
+}
+
+/// A block after the logical screen descriptor, borrowed from the file with its introducer
+#[derive(Debug, Clone, Copy)]
+struct Block<'a> {
+    introducer: u8,
+    /// Extension label, 0 for image descriptors
+    label: u8,
+    data: &'a [u8],
+}
+
+impl Block<'_> {
+    fn is_xmp(&self) -> bool {
+        self.introducer == EXTENSION
+            && self.label == APPLICATION
+            && self.data.get(2) == Some(&11)
+            && self.data.get(3..14) == Some(&XMP_APPLICATION[..])
+    }
+}
+
+/// Returns the offset just past a chain of sub-blocks starting at `offset`
+fn skip_sub_blocks(data: &[u8], mut offset: usize) -> Result<usize, XmpError> {
+    loop {
+        let size = *data.get(offset).otor(|| XmpErrorKind::InvalidGif)? as usize;
+        offset += 1 + size;
+        if size == 0 {
+            return Ok(offset);
+        }
+    }
+}
+
+/// Splits the file into the header with the global color table and the blocks up to the trailer
+fn blocks(data: &[u8]) -> Result<(&[u8], Vec<Block<'_>>), XmpError> {
+    if data.len() < 13 || !(data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a")) {
+        return Err(XmpErrorKind::InvalidGif.into());
+    }
+    let color_table = |packed: u8| {
+        if packed & 0x80 != 0 {
+            3 << ((packed & 0x07) + 1)
+        } else {
+            0
+        }
+    };
+    // Signature, logical screen descriptor and global color table
+    let header_len = 13 + color_table(data[10]);
+    let header = data.get(..header_len).otor(|| XmpErrorKind::InvalidGif)?;
+
+    let mut out = Vec::new();
+    let mut offset = header_len;
+    loop {
+        let start = offset;
+        let (introducer, label) = match data.get(offset) {
+            Some(&EXTENSION) => {
+                let label = *data.get(offset + 1).otor(|| XmpErrorKind::InvalidGif)?;
+                offset = skip_sub_blocks(data, offset + 2)?;
+                (EXTENSION, label)
+            }
+            Some(&IMAGE_DESCRIPTOR) => {
+                let packed = *data.get(offset + 9).otor(|| XmpErrorKind::InvalidGif)?;
+                // Descriptor, local color table and LZW minimum code size
+                offset = skip_sub_blocks(data, offset + 10 + color_table(packed) + 1)?;
+                (IMAGE_DESCRIPTOR, 0)
+            }
+            // Some encoders leave out the trailer
+            Some(&TRAILER) | None => return Ok((header, out)),
+            Some(_) => return Err(XmpErrorKind::InvalidGif.into()),
+        };
+        let data = data.get(start..offset).otor(|| XmpErrorKind::InvalidGif)?;
+        out.push(Block {
+            introducer,
+            label,
+            data,
+        });
+    }
+}
+
+pub(crate) fn __gif_xml(data: &[u8]) -> Result<Vec<u8>, XmpError> {
+    let (_, blocks) = blocks(data)?;
+    let block = blocks
+        .iter()
+        .find(|block| block.is_xmp())
+        .otor(|| XmpErrorKind::XMPMissing)?;
+    // The packet is stored raw after the application identifier, not as sub-blocks
+    let packet = &block.data[14..];
+    packet
+        .strip_suffix(&magic_trailer()[..])
+        .map(<[u8]>::to_vec)
+        .otor(|| XmpErrorKind::InvalidGif)
+        .map_err(Into::into)
+}
+
+/// Returns `data` with the xmp application extension replaced by one holding `xml`
+///
+/// The block is placed before the first image, ahead of its graphic control extension. GIF87a
+/// files are upgraded to GIF89a since they can't have extensions.
+pub(crate) fn __gif_write_xml(data: &[u8], xml: &[u8]) -> Result<Vec<u8>, XmpError> {
+    // A null byte would end the sub-blocks early for readers skipping the packet
+    if xml.contains(&0) {
+        return Err(XmpErrorKind::InvalidGif.into());
+    }
+    let (header, blocks) = blocks(data)?;
+    let blocks: Vec<_> = blocks.into_iter().filter(|block| !block.is_xmp()).collect();
+    let first_image = blocks
+        .iter()
+        .position(|block| block.introducer == IMAGE_DESCRIPTOR)
+        .unwrap_or(blocks.len());
+    let index = match first_image.checked_sub(1).map(|index| &blocks[index]) {
+        Some(block) if block.introducer == EXTENSION && block.label == GRAPHIC_CONTROL => {
+            first_image - 1
+        }
+        _ => first_image,
+    };
+
+    let mut out = Vec::with_capacity(data.len() + xml.len() + 14 + MAGIC_TRAILER_LEN);
+    out.extend_from_slice(b"GIF89a");
+    out.extend_from_slice(&header[6..]);
+    for (i, block) in blocks.iter().enumerate() {
+        if i == index {
+            write_xmp_block(&mut out, xml);
+        }
+        out.extend_from_slice(block.data);
+    }
+    if index == blocks.len() {
+        write_xmp_block(&mut out, xml);
+    }
+    out.push(TRAILER);
+    Ok(out)
+}
+
+fn write_xmp_block(out: &mut Vec<u8>, xml: &[u8]) {
+    out.extend_from_slice(&[EXTENSION, APPLICATION, 11]);
+    out.extend_from_slice(XMP_APPLICATION);
+    out.extend_from_slice(xml);
+    out.extend_from_slice(&magic_trailer());
+}
+
+impl UpdateResults {
+    pub fn update_gif(
+        &self,
+        path: impl AsRef<Path>,
+        options: UpdateOptions,
+    ) -> Result<(), XmpError> {
+        let mut reader = BufReader::new(std::fs::File::open(&path)?);
+        replace_file(&path, |writer| self.apply_gif(&mut reader, writer, options))
+    }
+
+    pub fn apply_gif<R: Read, W: Write>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+        options: UpdateOptions,
+    ) -> Result<(), XmpError> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        let xml = __gif_xml(&data).unwrap_or_else(|_e| DEFAULT_XML.as_bytes().to_vec());
+        let xml = self.update_xml(Cursor::new(xml), options)?;
+        writer.write_all(&__gif_write_xml(&data, &xml)?)?;
+        Ok(())
+    }
+}
+
+impl OptionalResults {
+    pub fn load_gif(path: impl AsRef<Path>) -> Result<Self, XmpError> {
+        Self::read_gif(&mut BufReader::new(std::fs::File::open(path)?))
+    }
+
+    pub fn read_gif<R: Read>(reader: &mut R) -> Result<Self, XmpError> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        Self::from_reader(Cursor::new(__gif_xml(&data)?))
+    }
+}
+
+/// A 2 frame 1x1 animation with a global color table and a looping extension
+#[cfg(test)]
+fn sample_gif() -> Vec<u8> {
+    let frame: &[u8] = &[
+        // Graphic control extension
+        0x21, 0xf9, 0x04, 0x04, 0x0a, 0x00, 0x00, 0x00,
+        // Image descriptor, LZW minimum code size and image data
+        0x2c, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x02, 0x02, 0x44, 0x01, 0x00,
+    ];
+    [
+        &b"GIF87a\x01\x00\x01\x00\x80\x00\x00"[..],
+        &[0xff, 0xff, 0xff, 0x00, 0x00, 0x00],
+        b"\x21\xff\x0bNETSCAPE2.0\x03\x01\x00\x00\x00",
+        frame,
+        frame,
+        &[TRAILER],
+    ]
+    .concat()
+}
+
+#[test]
+pub fn gif_write_xml() {
+    let original = sample_gif();
+    let data = __gif_write_xml(&original, DEFAULT_XML.as_bytes()).unwrap();
+    assert!(data.starts_with(b"GIF89a"));
+    assert_eq!(__gif_xml(&data).unwrap(), DEFAULT_XML.as_bytes());
+
+    let (_, before) = blocks(&original).unwrap();
+    let (_, after) = blocks(&data).unwrap();
+    assert_eq!(after.len(), before.len() + 1);
+    // Looping extension, xmp, then the frames with their graphic control extensions
+    assert!(after[1].is_xmp());
+    assert_eq!(after[2].label, GRAPHIC_CONTROL);
+    for (a, b) in after.iter().filter(|b| !b.is_xmp()).zip(&before) {
+        assert_eq!(a.data, b.data);
+    }
+
+    // The packet is replaced instead of added again
+    let updated = __gif_write_xml(&data, b"<x:xmpmeta/>").unwrap();
+    assert_eq!(__gif_xml(&updated).unwrap(), b"<x:xmpmeta/>");
+    assert_eq!(
+        blocks(&updated)
+            .unwrap()
+            .1
+            .iter()
+            .filter(|b| b.is_xmp())
+            .count(),
+        1
+    );
+}
+
+#[test]
+pub fn gif_update() {
+    let dir = std::env::temp_dir().join("xmp_gif_update");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("file.gif");
+    std::fs::write(&path, sample_gif()).unwrap();
+
+    let u = UpdateResults {
+        stars: Some(3),
+        subjects: Some(vec![String::from("Social")]),
+        ..Default::default()
+    };
+    u.update(&path).unwrap();
+    let r = OptionalResults::load(&path).unwrap();
+    assert_eq!(r.stars, Some(3));
+    assert_eq!(r.subjects, Some(vec![String::from("Social")]));
+}
@@ -0,0 +1,100 @@
+#[cfg(test)]
+use crate::isobmff::sample_isobmff;
+use crate::isobmff::{__isobmff_write_xml, __isobmff_xml};
+use crate::*;
+use std::io::Cursor;
+
+impl UpdateResults {
+    pub fn update_heif(
+        &self,
+        path: impl AsRef<Path>,
+        options: UpdateOptions,
+    ) -> Result<(), XmpError> {
+        let mut reader = BufReader::new(std::fs::File::open(&path)?);
+        replace_file(&path, |writer| {
+            self.apply_heif(&mut reader, writer, options)
+        })
+    }
+
+    pub fn apply_heif<R: Read, W: Write>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+        options: UpdateOptions,
+    ) -> Result<(), XmpError> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        let xml = __isobmff_xml(&data).unwrap_or_else(|_e| DEFAULT_XML.as_bytes().to_vec());
+        let xml = self.update_xml(Cursor::new(xml), options)?;
+        __isobmff_write_xml(&data, &xml, writer)
+    }
+}
+
+impl OptionalResults {
+    pub fn load_heif(path: impl AsRef<Path>) -> Result<Self, XmpError> {
+        Self::read_heif(&mut BufReader::new(std::fs::File::open(path)?))
+    }
+
+    pub fn read_heif<R: Read>(reader: &mut R) -> Result<Self, XmpError> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        Self::from_reader(Cursor::new(__isobmff_xml(&data)?))
+    }
+}
+
+#[test]
+pub fn heif_write_xml() {
+    use crate::isobmff::Meta;
+
+    let original = sample_isobmff(b"heic", b"hvc1", b"hvcC");
+    let mut data = Vec::new();
+    __isobmff_write_xml(&original, DEFAULT_XML.as_bytes(), &mut data).unwrap();
+    assert_eq!(__isobmff_xml(&data).unwrap(), DEFAULT_XML.as_bytes());
+    let meta = Meta::parse(&data).unwrap();
+    assert_eq!(meta.item_data(&data, 1).unwrap(), b"image data");
+
+    // The previous packet is replaced instead of appended to
+    let mut updated = Vec::new();
+    __isobmff_write_xml(&data, &DEFAULT_XML.as_bytes()[..100], &mut updated).unwrap();
+    assert_eq!(
+        __isobmff_xml(&updated).unwrap(),
+        &DEFAULT_XML.as_bytes()[..100]
+    );
+    assert_eq!(updated.len(), data.len() - (DEFAULT_XML.len() - 100));
+    let meta = Meta::parse(&updated).unwrap();
+    assert_eq!(meta.item_data(&updated, 1).unwrap(), b"image data");
+    assert_eq!(meta.items().unwrap().len(), 2);
+}
+
+#[test]
+pub fn heif_update() {
+    let dir = std::env::temp_dir().join("xmp_heif_update");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("file.heic");
+    std::fs::write(&path, sample_isobmff(b"heic", b"hvc1", b"hvcC")).unwrap();
+
+    let u = UpdateResults {
+        stars: Some(4),
+        subjects: Some(vec![String::from("Selected")]),
+        ..Default::default()
+    };
+    u.update(&path).unwrap();
+    let r = OptionalResults::load(&path).unwrap();
+    assert_eq!(r.stars, Some(4));
+    assert_eq!(r.subjects, Some(vec![String::from("Selected")]));
+}
+
+#[test]
+pub fn heif_empty_iref() {
+    let original = sample_isobmff(b"heic", b"hvc1", b"hvcC");
+    // Append an iref box without its version and flags to the end of meta
+    let ftyp = u32::from_be_bytes(original[..4].try_into().unwrap()) as usize;
+    let meta = u32::from_be_bytes(original[ftyp..ftyp + 4].try_into().unwrap()) as usize;
+    let mut data = original[..ftyp + meta].to_vec();
+    data[ftyp..ftyp + 4].copy_from_slice(&(meta as u32 + 8).to_be_bytes());
+    data.extend(crate::isobmff::encode_box(b"iref", &[]));
+    data.extend_from_slice(&original[ftyp + meta..]);
+
+    let mut out = Vec::new();
+    assert!(__isobmff_write_xml(&data, DEFAULT_XML.as_bytes(), &mut out).is_err());
+}
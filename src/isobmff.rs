@@ -0,0 +1,752 @@
+use crate::*;
+
+/// Content type of the `mime` item holding the xmp packet
+pub(crate) const XMP_CONTENT_TYPE: &str = "application/rdf+xml";
+
+/// Usertype of the `uuid` box holding the xmp packet in CR3 and MP4 files
+pub(crate) const XMP_UUID: [u8; 16] = [
+    0xbe, 0x7a, 0xcf, 0xcb, 0x97, 0xa9, 0x42, 0xe8, 0x9c, 0x71, 0x99, 0x94, 0x91, 0xe3, 0xaf, 0xac,
+];
+
+/// A box borrowed from the data of its parent
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct IsoBox<'a> {
+    pub kind: [u8; 4],
+    /// Offset of the box in the data of its parent
+    pub offset: usize,
+    /// Length of the header including the largesize and the usertype
+    pub header: usize,
+    /// The whole box including the header
+    pub data: &'a [u8],
+}
+
+impl<'a> IsoBox<'a> {
+    pub fn body(&self) -> &'a [u8] {
+        &self.data[self.header..]
+    }
+
+    pub fn end(&self) -> usize {
+        self.offset + self.data.len()
+    }
+}
+
+/// Header of a top level box read from a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct BoxHeader {
+    pub kind: [u8; 4],
+    pub offset: u64,
+    /// Size of the whole box, boxes extending to the end of the file get their actual size
+    pub size: u64,
+    /// Length of the header including the largesize and the usertype
+    pub header: u64,
+    pub usertype: Option<[u8; 16]>,
+    /// Whether the size field is 0, meaning the box extends to the end of the file
+    pub to_end: bool,
+}
+
+impl BoxHeader {
+    /// Reads the whole box including the header
+    pub fn read<R: Read + Seek>(&self, reader: &mut R) -> Result<Vec<u8>, XmpError> {
+        let mut data = vec![0; self.size as usize];
+        reader.seek(SeekFrom::Start(self.offset))?;
+        reader.read_exact(&mut data)?;
+        Ok(data)
+    }
+}
+
+/// Reads the headers of the top level boxes, seeking over their bodies
+pub(crate) fn read_boxes<R: Read + Seek>(reader: &mut R) -> Result<Vec<BoxHeader>, XmpError> {
+    let end = reader.seek(SeekFrom::End(0))?;
+    let mut out = Vec::new();
+    let mut offset = 0;
+    while offset + 8 <= end {
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut header = [0; 8];
+        reader.read_exact(&mut header)?;
+        let mut header_len = 8;
+        let mut kind = [0; 4];
+        kind.copy_from_slice(&header[4..]);
+        let (size, to_end) = match u32::from_be_bytes([header[0], header[1], header[2], header[3]])
+        {
+            0 => (end - offset, true),
+            1 => {
+                let mut size = [0; 8];
+                reader.read_exact(&mut size)?;
+                header_len += 8;
+                (u64::from_be_bytes(size), false)
+            }
+            size => (size.into(), false),
+        };
+        let mut usertype = None;
+        if &kind == b"uuid" && size >= header_len + 16 {
+            let mut uuid = [0; 16];
+            reader.read_exact(&mut uuid)?;
+            header_len += 16;
+            usertype = Some(uuid);
+        }
+        if size < header_len || offset + size > end {
+            return Err(XmpErrorKind::InvalidIsobmff.into());
+        }
+        out.push(BoxHeader {
+            kind,
+            offset,
+            size,
+            header: header_len,
+            usertype,
+            to_end,
+        });
+        offset += size;
+    }
+    Ok(out)
+}
+
+/// Reads the body of the top level `uuid` box with the given usertype without loading the
+/// other boxes
+pub(crate) fn read_uuid<R: Read + Seek>(
+    reader: &mut R,
+    usertype: &[u8; 16],
+) -> Result<Vec<u8>, XmpError> {
+    let header = read_boxes(reader)?
+        .into_iter()
+        .find(|b| b.usertype.as_ref() == Some(usertype))
+        .otor(|| XmpErrorKind::XMPMissing)?;
+    Ok(header.read(reader)?.split_off(header.header as usize))
+}
+
+/// Splits `data` into the boxes it contains
+pub(crate) fn boxes(data: &[u8]) -> Result<Vec<IsoBox<'_>>, XmpError> {
+    let mut out = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let rest = &data[offset..];
+        let mut reader = ByteReader::new(rest);
+        let size = reader.u32()?;
+        let kind = reader.array()?;
+        let size = match size {
+            // The box extends to the end of the data
+            0 => rest.len() as u64,
+            1 => reader.u64()?,
+            size => size.into(),
+        };
+        if &kind == b"uuid" {
+            reader.bytes(16)?;
+        }
+        let header = reader.pos;
+        if size < header as u64 || size > rest.len() as u64 {
+            return Err(XmpErrorKind::InvalidIsobmff.into());
+        }
+        out.push(IsoBox {
+            kind,
+            offset,
+            header,
+            data: &rest[..size as usize],
+        });
+        offset += size as usize;
+    }
+    Ok(out)
+}
+
+pub(crate) fn find<'a>(boxes: &[IsoBox<'a>], kind: &[u8; 4]) -> Option<IsoBox<'a>> {
+    boxes.iter().find(|b| &b.kind == kind).copied()
+}
+
+/// Encodes a box, using a largesize only if the box doesn't fit a 32 bit size
+pub(crate) fn encode_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(body.len() + 16);
+    if let Ok(size) = u32::try_from(body.len() + 8) {
+        out.extend_from_slice(&size.to_be_bytes());
+        out.extend_from_slice(kind);
+    } else {
+        out.extend_from_slice(&1u32.to_be_bytes());
+        out.extend_from_slice(kind);
+        out.extend_from_slice(&(body.len() as u64 + 16).to_be_bytes());
+    }
+    out.extend_from_slice(body);
+    out
+}
+
+/// Big endian reader over a box body
+pub(crate) struct ByteReader<'a> {
+    data: &'a [u8],
+    pub pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], XmpError> {
+        let bytes = self
+            .pos
+            .checked_add(len)
+            .and_then(|end| self.data.get(self.pos..end))
+            .otor(|| XmpErrorKind::InvalidIsobmff)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn array<const N: usize>(&mut self) -> Result<[u8; N], XmpError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.bytes(N)?);
+        Ok(array)
+    }
+
+    pub fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.data[self.pos..];
+        self.pos = self.data.len();
+        rest
+    }
+
+    pub fn u8(&mut self) -> Result<u8, XmpError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, XmpError> {
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, XmpError> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, XmpError> {
+        Ok(u64::from_be_bytes(self.array()?))
+    }
+
+    /// Reads a field of 0, 2, 4 or 8 bytes
+    pub fn sized(&mut self, size: u8) -> Result<u64, XmpError> {
+        Ok(match size {
+            0 => 0,
+            2 => self.u16()?.into(),
+            4 => self.u32()?.into(),
+            8 => self.u64()?,
+            _ => return Err(XmpErrorKind::InvalidIsobmff.into()),
+        })
+    }
+
+    /// Reads a null terminated string
+    pub fn cstr(&mut self) -> Result<&'a str, XmpError> {
+        let rest = &self.data[self.pos..];
+        let len = rest
+            .iter()
+            .position(|b| *b == 0)
+            .otor(|| XmpErrorKind::InvalidIsobmff)?;
+        self.pos += len + 1;
+        std::str::from_utf8(&rest[..len]).map_err(|_| XmpErrorKind::InvalidIsobmff.into())
+    }
+}
+
+fn put_sized(out: &mut Vec<u8>, size: u8, value: u64) -> Result<(), XmpError> {
+    let too_large = || XmpError::from(XmpErrorKind::InvalidIsobmff);
+    match size {
+        0 if value == 0 => (),
+        2 => out.extend_from_slice(&u16::try_from(value).map_err(|_| too_large())?.to_be_bytes()),
+        4 => out.extend_from_slice(&u32::try_from(value).map_err(|_| too_large())?.to_be_bytes()),
+        8 => out.extend_from_slice(&value.to_be_bytes()),
+        _ => return Err(too_large()),
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ItemInfo {
+    pub id: u32,
+    pub item_type: [u8; 4],
+    pub content_type: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Extent {
+    pub index: u64,
+    pub offset: u64,
+    /// A length of 0 means the extent goes to the end of the data
+    pub length: u64,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ItemLocation {
+    pub id: u32,
+    /// 0 for offsets in the file, 1 for offsets in the `idat` box and 2 for offsets in an item
+    pub construction_method: u8,
+    pub data_reference_index: u16,
+    pub base_offset: u64,
+    pub extents: Vec<Extent>,
+}
+
+/// The `iloc` item location box
+#[derive(Debug, Clone)]
+pub(crate) struct Iloc {
+    pub version: u8,
+    pub offset_size: u8,
+    pub length_size: u8,
+    pub base_offset_size: u8,
+    pub index_size: u8,
+    pub items: Vec<ItemLocation>,
+}
+
+impl Iloc {
+    pub fn parse(body: &[u8]) -> Result<Self, XmpError> {
+        let mut reader = ByteReader::new(body);
+        let version = reader.u8()?;
+        reader.bytes(3)?;
+        let sizes = reader.u16()?;
+        let mut iloc = Self {
+            version,
+            offset_size: (sizes >> 12) as u8,
+            length_size: (sizes >> 8 & 0xf) as u8,
+            base_offset_size: (sizes >> 4 & 0xf) as u8,
+            index_size: if version > 0 { (sizes & 0xf) as u8 } else { 0 },
+            items: Vec::new(),
+        };
+        let count = if version < 2 {
+            reader.u16()?.into()
+        } else {
+            reader.u32()?
+        };
+        for _ in 0..count {
+            let id = if version < 2 {
+                reader.u16()?.into()
+            } else {
+                reader.u32()?
+            };
+            let construction_method = if version > 0 {
+                (reader.u16()? & 0xf) as u8
+            } else {
+                0
+            };
+            let data_reference_index = reader.u16()?;
+            let base_offset = reader.sized(iloc.base_offset_size)?;
+            let extent_count = reader.u16()?;
+            let mut extents = Vec::with_capacity(extent_count.into());
+            for _ in 0..extent_count {
+                extents.push(Extent {
+                    index: reader.sized(iloc.index_size)?,
+                    offset: reader.sized(iloc.offset_size)?,
+                    length: reader.sized(iloc.length_size)?,
+                });
+            }
+            iloc.items.push(ItemLocation {
+                id,
+                construction_method,
+                data_reference_index,
+                base_offset,
+                extents,
+            });
+        }
+        Ok(iloc)
+    }
+
+    /// Encodes the body of the box
+    pub fn encode(&self) -> Result<Vec<u8>, XmpError> {
+        let mut out = vec![self.version, 0, 0, 0];
+        let index_size = if self.version > 0 { self.index_size } else { 0 };
+        out.push(self.offset_size << 4 | self.length_size);
+        out.push(self.base_offset_size << 4 | index_size);
+        if self.version < 2 {
+            put_sized(&mut out, 2, self.items.len() as u64)?;
+        } else {
+            put_sized(&mut out, 4, self.items.len() as u64)?;
+        }
+        for item in &self.items {
+            put_sized(
+                &mut out,
+                if self.version < 2 { 2 } else { 4 },
+                item.id.into(),
+            )?;
+            if self.version > 0 {
+                put_sized(&mut out, 2, item.construction_method.into())?;
+            }
+            put_sized(&mut out, 2, item.data_reference_index.into())?;
+            put_sized(&mut out, self.base_offset_size, item.base_offset)?;
+            put_sized(&mut out, 2, item.extents.len() as u64)?;
+            for extent in &item.extents {
+                put_sized(&mut out, index_size, extent.index)?;
+                put_sized(&mut out, self.offset_size, extent.offset)?;
+                put_sized(&mut out, self.length_size, extent.length)?;
+            }
+        }
+        Ok(out)
+    }
+
+    pub fn item(&self, id: u32) -> Option<&ItemLocation> {
+        self.items.iter().find(|item| item.id == id)
+    }
+}
+
+/// The top level boxes of a file along with its `meta` box
+pub(crate) struct Meta<'a> {
+    pub boxes: Vec<IsoBox<'a>>,
+    pub meta: IsoBox<'a>,
+    /// Offsets are relative to the body of `meta` after the version and flags
+    pub children: Vec<IsoBox<'a>>,
+}
+
+impl<'a> Meta<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, XmpError> {
+        let top = boxes(data)?;
+        let meta = find(&top, b"meta").otor(|| XmpErrorKind::XMPMissing)?;
+        let children = boxes(meta.body().get(4..).otor(|| XmpErrorKind::InvalidIsobmff)?)?;
+        Ok(Self {
+            boxes: top,
+            meta,
+            children,
+        })
+    }
+
+    fn child(&self, kind: &[u8; 4]) -> Result<IsoBox<'a>, XmpError> {
+        Ok(find(&self.children, kind).otor(|| XmpErrorKind::InvalidIsobmff)?)
+    }
+
+    pub fn items(&self) -> Result<Vec<ItemInfo>, XmpError> {
+        let iinf = self.child(b"iinf")?;
+        let mut reader = ByteReader::new(iinf.body());
+        let version = reader.u8()?;
+        reader.bytes(3)?;
+        if version == 0 {
+            reader.u16()?;
+        } else {
+            reader.u32()?;
+        }
+        let mut items = Vec::new();
+        for infe in boxes(reader.rest())?.iter().filter(|b| &b.kind == b"infe") {
+            let mut reader = ByteReader::new(infe.body());
+            let version = reader.u8()?;
+            reader.bytes(3)?;
+            let id = if version == 3 {
+                reader.u32()?
+            } else {
+                reader.u16()?.into()
+            };
+            // item_protection_index
+            reader.u16()?;
+            if version >= 2 {
+                let item_type = reader.array()?;
+                reader.cstr()?;
+                let content_type = if &item_type == b"mime" {
+                    Some(reader.cstr()?.to_owned())
+                } else {
+                    None
+                };
+                items.push(ItemInfo {
+                    id,
+                    item_type,
+                    content_type,
+                });
+            } else {
+                reader.cstr()?;
+                items.push(ItemInfo {
+                    id,
+                    item_type: *b"mime",
+                    content_type: Some(reader.cstr()?.to_owned()),
+                });
+            }
+        }
+        Ok(items)
+    }
+
+    pub fn iloc(&self) -> Result<Iloc, XmpError> {
+        Iloc::parse(self.child(b"iloc")?.body())
+    }
+
+    pub fn primary_item(&self) -> Result<Option<u32>, XmpError> {
+        let pitm = match find(&self.children, b"pitm") {
+            Some(pitm) => pitm,
+            None => return Ok(None),
+        };
+        let mut reader = ByteReader::new(pitm.body());
+        let version = reader.u8()?;
+        reader.bytes(3)?;
+        Ok(Some(if version == 0 {
+            reader.u16()?.into()
+        } else {
+            reader.u32()?
+        }))
+    }
+
+    pub fn xmp_item(&self) -> Result<Option<u32>, XmpError> {
+        Ok(self
+            .items()?
+            .into_iter()
+            .find(|item| {
+                &item.item_type == b"mime" && item.content_type.as_deref() == Some(XMP_CONTENT_TYPE)
+            })
+            .map(|item| item.id))
+    }
+
+    /// Concatenates the extents of an item
+    pub fn item_data(&self, data: &[u8], id: u32) -> Result<Vec<u8>, XmpError> {
+        let location = self
+            .iloc()?
+            .item(id)
+            .cloned()
+            .otor(|| XmpErrorKind::InvalidIsobmff)?;
+        let source = match (location.construction_method, location.data_reference_index) {
+            (0, 0) => data,
+            (1, _) => self.child(b"idat")?.body(),
+            // Data in other files or other items isn't supported
+            _ => return Err(XmpErrorKind::InvalidIsobmff.into()),
+        };
+        let mut out = Vec::new();
+        for extent in location.extents {
+            let start = location
+                .base_offset
+                .checked_add(extent.offset)
+                .otor(|| XmpErrorKind::InvalidIsobmff)?;
+            let end = if extent.length == 0 {
+                source.len() as u64
+            } else {
+                start
+                    .checked_add(extent.length)
+                    .otor(|| XmpErrorKind::InvalidIsobmff)?
+            };
+            out.extend_from_slice(
+                source
+                    .get(start as usize..end as usize)
+                    .otor(|| XmpErrorKind::InvalidIsobmff)?,
+            );
+        }
+        Ok(out)
+    }
+
+    /// Encodes the `meta` box with `iloc` replaced, and an `infe` and a `cdsc` reference added
+    /// for `new_item` if set
+    fn encode(&self, iloc: &Iloc, new_item: Option<u32>) -> Result<Vec<u8>, XmpError> {
+        let mut body = self.meta.body()[..4].to_vec();
+        let primary = self.primary_item()?;
+        let mut has_iref = false;
+        for child in &self.children {
+            match (&child.kind, new_item) {
+                (b"iloc", _) => body.extend(encode_box(b"iloc", &iloc.encode()?)),
+                (b"iinf", Some(id)) => {
+                    let mut reader = ByteReader::new(child.body());
+                    let version = reader.u8()?;
+                    reader.bytes(3)?;
+                    let count = if version == 0 {
+                        reader.u16()?.into()
+                    } else {
+                        reader.u32()?
+                    } + 1;
+                    let mut iinf = match u16::try_from(count) {
+                        Ok(count) if version == 0 => {
+                            [&[0, 0, 0, 0], &count.to_be_bytes()[..]].concat()
+                        }
+                        _ => [&[version.max(1), 0, 0, 0], &count.to_be_bytes()[..]].concat(),
+                    };
+                    iinf.extend_from_slice(reader.rest());
+                    iinf.extend(encode_box(b"infe", &xmp_infe(id)));
+                    body.extend(encode_box(b"iinf", &iinf));
+                }
+                (b"iref", Some(id)) => {
+                    has_iref = true;
+                    let mut iref = child.body().to_vec();
+                    if let Some(primary) = primary {
+                        let version = *iref.first().otor(|| XmpErrorKind::InvalidIsobmff)?;
+                        iref.extend(encode_box(b"cdsc", &cdsc(version, id, primary)?));
+                    }
+                    body.extend(encode_box(b"iref", &iref));
+                }
+                _ => body.extend_from_slice(child.data),
+            }
+        }
+        if let (Some(id), Some(primary), false) = (new_item, primary, has_iref) {
+            let mut iref = vec![0, 0, 0, 0];
+            iref.extend(encode_box(b"cdsc", &cdsc(0, id, primary)?));
+            body.extend(encode_box(b"iref", &iref));
+        }
+        Ok(encode_box(b"meta", &body))
+    }
+}
+
+/// Body of a version 2 / 3 `infe` box for the xmp item
+fn xmp_infe(id: u32) -> Vec<u8> {
+    let mut out = Vec::new();
+    if let Ok(id) = u16::try_from(id) {
+        out.extend_from_slice(&[2, 0, 0, 0]);
+        out.extend_from_slice(&id.to_be_bytes());
+    } else {
+        out.extend_from_slice(&[3, 0, 0, 0]);
+        out.extend_from_slice(&id.to_be_bytes());
+    }
+    // item_protection_index
+    out.extend_from_slice(&[0, 0]);
+    out.extend_from_slice(b"mime");
+    // Empty item_name
+    out.push(0);
+    out.extend_from_slice(XMP_CONTENT_TYPE.as_bytes());
+    out.push(0);
+    out
+}
+
+/// Body of a `cdsc` reference from the xmp item to the image it describes
+fn cdsc(iref_version: u8, from: u32, to: u32) -> Result<Vec<u8>, XmpError> {
+    let size = if iref_version == 0 { 2 } else { 4 };
+    let mut out = Vec::new();
+    put_sized(&mut out, size, from.into())?;
+    put_sized(&mut out, 2, 1)?;
+    put_sized(&mut out, size, to.into())?;
+    Ok(out)
+}
+
+/// Reads the `mime` item with the `application/rdf+xml` content type
+pub(crate) fn __isobmff_xml(data: &[u8]) -> Result<Vec<u8>, XmpError> {
+    let meta = Meta::parse(data)?;
+    let id = meta.xmp_item()?.otor(|| XmpErrorKind::XMPMissing)?;
+    meta.item_data(data, id)
+}
+
+/// Writes `data` with the xmp item replaced by `xml` to `writer`
+///
+/// The packet is stored in a new `mdat` at the end of the file and the `meta` box is rewritten
+/// with the new item location. Offsets of the items stored after `meta` are shifted by the
+/// change in its size, everything else is copied as is.
+pub(crate) fn __isobmff_write_xml<W: Write>(
+    data: &[u8],
+    xml: &[u8],
+    writer: &mut W,
+) -> Result<(), XmpError> {
+    let meta = Meta::parse(data)?;
+    // Chunk offsets of image sequences aren't rewritten
+    if find(&meta.boxes, b"moov").is_some() {
+        return Err(XmpErrorKind::InvalidIsobmff.into());
+    }
+    let mut iloc = meta.iloc()?;
+    let existing = meta.xmp_item()?;
+    let id = match existing {
+        Some(id) => id,
+        None => {
+            meta.items()?
+                .iter()
+                .map(|item| item.id)
+                .chain(iloc.items.iter().map(|item| item.id))
+                .max()
+                .unwrap_or(0)
+                + 1
+        }
+    };
+
+    // Drop the `mdat` holding only the previous packet so repeated updates don't grow the file
+    let tail = meta.boxes.last().filter(|tail| {
+        &tail.kind == b"mdat"
+            && tail.offset >= meta.meta.end()
+            && existing.and_then(|id| iloc.item(id)).is_some_and(|item| {
+                item.construction_method == 0
+                    && item.data_reference_index == 0
+                    && item.extents.len() == 1
+                    && item.base_offset + item.extents[0].offset
+                        == (tail.offset + tail.header) as u64
+                    && item.extents[0].length == tail.body().len() as u64
+            })
+    });
+    let kept = &data[meta.meta.end()..tail.map_or(data.len(), |tail| tail.offset)];
+
+    // Field sizes are fixed before the offsets are known so the size of `iloc` doesn't change
+    if data.len() as u64 + xml.len() as u64 + 1024 > u32::MAX.into() {
+        iloc.offset_size = 8;
+        iloc.length_size = 8;
+        if iloc.base_offset_size > 0 {
+            iloc.base_offset_size = 8;
+        }
+    } else {
+        iloc.offset_size = iloc.offset_size.max(4);
+        iloc.length_size = iloc.length_size.max(4);
+    }
+    if id > u16::MAX.into() {
+        iloc.version = 2;
+    }
+    let location = ItemLocation {
+        id,
+        construction_method: 0,
+        data_reference_index: 0,
+        base_offset: 0,
+        extents: vec![Extent {
+            index: 0,
+            offset: 0,
+            length: xml.len() as u64,
+        }],
+    };
+    match iloc.items.iter_mut().find(|item| item.id == id) {
+        Some(item) => *item = location,
+        None => iloc.items.push(location),
+    }
+
+    let new_item = existing.is_none().then_some(id);
+    let meta_len = meta.encode(&iloc, new_item)?.len() as u64;
+    let meta_end = meta.meta.end() as u64;
+    let shift = |offset: u64| -> Result<u64, XmpError> {
+        Ok((offset + meta_len)
+            .checked_sub(meta.meta.data.len() as u64)
+            .otor(|| XmpErrorKind::InvalidIsobmff)?)
+    };
+    for item in iloc.items.iter_mut().filter(|item| {
+        item.id != id && item.construction_method == 0 && item.data_reference_index == 0
+    }) {
+        if item.base_offset >= meta_end {
+            item.base_offset = shift(item.base_offset)?;
+        } else {
+            for extent in item.extents.iter_mut() {
+                if item.base_offset + extent.offset >= meta_end {
+                    extent.offset = shift(extent.offset)?;
+                }
+            }
+        }
+    }
+    let mdat = encode_box(b"mdat", xml);
+    let packet_offset =
+        meta.meta.offset as u64 + meta_len + kept.len() as u64 + (mdat.len() - xml.len()) as u64;
+    if let Some(item) = iloc.items.iter_mut().find(|item| item.id == id) {
+        item.extents[0].offset = packet_offset;
+    }
+
+    writer.write_all(&data[..meta.meta.offset])?;
+    writer.write_all(&meta.encode(&iloc, new_item)?)?;
+    writer.write_all(kept)?;
+    writer.write_all(&mdat)?;
+    Ok(())
+}
+
+/// Builds a file with a single image item stored in `mdat` after `meta`, with its decoder
+/// configuration and `ispe` properties
+#[cfg(test)]
+pub(crate) fn sample_isobmff(brand: &[u8; 4], item_type: &[u8; 4], config: &[u8; 4]) -> Vec<u8> {
+    let ftyp = encode_box(
+        b"ftyp",
+        &[&brand[..], &[0, 0, 0, 0], b"mif1", brand].concat(),
+    );
+    let hdlr = encode_box(b"hdlr", &[&[0; 8][..], b"pict", &[0; 13]].concat());
+    let pitm = encode_box(b"pitm", &[0, 0, 0, 0, 0, 1]);
+    let infe = encode_box(
+        b"infe",
+        &[&[2, 0, 0, 0, 0, 1, 0, 0][..], item_type, &[0]].concat(),
+    );
+    let iinf = encode_box(b"iinf", &[&[0, 0, 0, 0, 0, 1][..], &infe].concat());
+    let ipco = [
+        encode_box(config, b"decoder configuration"),
+        encode_box(b"ispe", &[0, 0, 0, 0, 0, 0, 0, 64, 0, 0, 0, 48]),
+    ]
+    .concat();
+    let ipma = encode_box(b"ipma", &[0, 0, 0, 0, 0, 0, 0, 1, 0, 1, 2, 0x81, 0x02]);
+    let iprp = encode_box(b"iprp", &[encode_box(b"ipco", &ipco), ipma].concat());
+    let iloc = |offset: u32| {
+        let body = [
+            &[0, 0, 0, 0, 0x44, 0x00, 0, 1, 0, 1, 0, 0, 0, 1][..],
+            &offset.to_be_bytes(),
+            &10u32.to_be_bytes(),
+        ]
+        .concat();
+        encode_box(b"iloc", &body)
+    };
+    let meta = |offset| {
+        encode_box(
+            b"meta",
+            &[&[0, 0, 0, 0][..], &hdlr, &pitm, &iinf, &iprp, &iloc(offset)].concat(),
+        )
+    };
+    let offset = ftyp.len() + meta(0).len() + 8;
+    [
+        ftyp,
+        meta(offset as u32),
+        encode_box(b"mdat", b"image data"),
+    ]
+    .concat()
+}
@@ -1,149 +1,613 @@
 use crate::*;
 use exif::{Context, Field, In, Tag, Value};
-use img_parts::ImageEXIF;
-use jfifdump::{Reader, SegmentKind};
-use std::io::BufReader;
+use md5::{Digest, Md5};
 use std::io::Cursor;
 
+pub(crate) mod markers {
+    pub const SOI: u8 = 0xd8;
+    pub const EOI: u8 = 0xd9;
+    pub const SOS: u8 = 0xda;
+    pub const APP0: u8 = 0xe0;
+    pub const APP1: u8 = 0xe1;
+    /// Markers without a length or contents
+    pub const TEM: u8 = 0x01;
+    pub const RST0: u8 = 0xd0;
+    pub const RST7: u8 = 0xd7;
+
+    #[cfg(test)]
+    pub const APP2: u8 = 0xe2;
+    #[cfg(test)]
+    pub const APP13: u8 = 0xed;
+    #[cfg(test)]
+    pub const DQT: u8 = 0xdb;
+    #[cfg(test)]
+    pub const COM: u8 = 0xfe;
+}
+
 const EXIF_DATA_PREFIX: &[u8; 6] = b"Exif\0\0";
+/// Signature of the standard xmp APP1 segment
+const XMP_SIGNATURE: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+/// Signature of the APP1 segments holding the extended xmp as per the XMP spec part 3
+const EXTENSION_SIGNATURE: &[u8] = b"http://ns.adobe.com/xmp/extension/\0";
+/// Segment lengths are a u16 which includes its own 2 bytes
+const MAX_SEGMENT: usize = 65533;
+/// Largest packet that fits in the standard segment
+const MAX_PACKET: usize = MAX_SEGMENT - XMP_SIGNATURE.len();
+/// Each extension segment holds the 32 byte GUID, the full length and the offset of its chunk
+const MAX_EXTENSION_CHUNK: usize = MAX_SEGMENT - EXTENSION_SIGNATURE.len() - 32 - 8;
+
+make_item!(XMPNOTE, "HasExtendedXMP");
+
+/// A marker segment of the jpeg header, everything up to and including SOS
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Segment {
+    pub marker: u8,
+    pub contents: Vec<u8>,
+}
+
+impl Segment {
+    fn new(marker: u8, contents: Vec<u8>) -> Self {
+        Self { marker, contents }
+    }
+
+    fn standalone(marker: u8) -> bool {
+        marker == markers::TEM || (markers::RST0..=markers::RST7).contains(&marker)
+    }
+}
 
-pub(crate) fn __jpeg_load_xml(path: impl AsRef<Path>) -> Result<Vec<u8>, XmpError> {
-    // First open a buffered reader
-    // Check if the file has a exif header or a jfif header
-    // Its possible for files to have a exif header while also having a jfif header
-    // So check for the exif header first
-    let mut jpeg = img_parts::jpeg::Jpeg::from_bytes(std::fs::read(&path)?.into())?;
-    // HACK:Put the Exif data in first position of jpeg segments
-    jpeg.segments_mut().sort_by(|seg1, _| {
-        if seg1.contents().starts_with(EXIF_DATA_PREFIX) {
-            std::cmp::Ordering::Less
+/// Reads the segments up to and including SOS, leaving the reader at the entropy coded data
+///
+/// Files without image data end at EOI which isn't returned.
+pub(crate) fn read_header<R: Read>(reader: &mut R) -> Result<Vec<Segment>, XmpError> {
+    read_segments(reader, |_| true)
+}
+
+/// Like [`read_header`] but only keeps the segments with a marker accepted by `keep`, the
+/// contents of the others are skipped without being buffered
+fn read_segments<R: Read>(
+    reader: &mut R,
+    keep: impl Fn(u8) -> bool,
+) -> Result<Vec<Segment>, XmpError> {
+    let mut bytes = [0; 2];
+    reader.read_exact(&mut bytes)?;
+    if bytes != [0xff, markers::SOI] {
+        return Err(XmpErrorKind::InvalidJpeg.into());
+    }
+    let mut segments = Vec::new();
+    loop {
+        reader.read_exact(&mut bytes)?;
+        if bytes[0] != 0xff {
+            return Err(XmpErrorKind::InvalidJpeg.into());
+        }
+        // Any number of 0xff fill bytes may precede a marker
+        let mut marker = bytes[1];
+        while marker == 0xff {
+            reader.read_exact(&mut bytes[1..])?;
+            marker = bytes[1];
+        }
+        if marker == markers::EOI {
+            return Ok(segments);
+        }
+        if Segment::standalone(marker) {
+            segments.push(Segment::new(marker, Vec::new()));
+            continue;
+        }
+        reader.read_exact(&mut bytes)?;
+        let length = usize::from(u16::from_be_bytes(bytes))
+            .checked_sub(2)
+            .otor(|| XmpErrorKind::InvalidJpeg)?;
+        if keep(marker) {
+            let mut contents = vec![0; length];
+            reader.read_exact(&mut contents)?;
+            segments.push(Segment::new(marker, contents));
         } else {
-            std::cmp::Ordering::Equal
+            let skipped = std::io::copy(&mut reader.take(length as u64), &mut std::io::sink())?;
+            if skipped != length as u64 {
+                return Err(XmpErrorKind::InvalidJpeg.into());
+            }
         }
-    });
-    if let Some(exif_data) = jpeg.exif() {
+        if marker == markers::SOS {
+            return Ok(segments);
+        }
+    }
+}
+
+/// Writes SOI and the segments, the entropy coded data is expected to follow
+pub(crate) fn write_header<W: Write>(writer: &mut W, segments: &[Segment]) -> Result<(), XmpError> {
+    writer.write_all(&[0xff, markers::SOI])?;
+    for segment in segments {
+        writer.write_all(&[0xff, segment.marker])?;
+        if !Segment::standalone(segment.marker) {
+            let length = u16::try_from(segment.contents.len() + 2)
+                .map_err(|_| XmpErrorKind::PacketTooLarge)?;
+            writer.write_all(&length.to_be_bytes())?;
+            writer.write_all(&segment.contents)?;
+        }
+    }
+    Ok(())
+}
+
+/// Uppercase hex MD5 of the extended packet used to tie it to the main one
+fn guid(extended: &[u8]) -> String {
+    Md5::digest(extended)
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect()
+}
+
+fn description_mut(xmpmeta: &mut Element) -> Result<&mut Element, XmpError> {
+    Ok(xmpmeta
+        .get_child_mut("RDF", RDF)
+        .and_then(|rdf| rdf.get_child_mut("Description", RDF))
+        .otor(|| XmpErrorKind::ChildNotFound)?)
+}
+
+fn serialize(element: &Element) -> Result<Vec<u8>, XmpError> {
+    let mut xml = Vec::new();
+    element.write_to(&mut xml)?;
+    Ok(xml)
+}
+
+/// Reassembles the extended xmp with the given GUID from the contents of the APP1 segments
+///
+/// Returns `None` if chunks are missing or the MD5 doesn't match the GUID
+fn extended_xmp<'a>(segments: impl Iterator<Item = &'a [u8]>, guid_: &str) -> Option<Vec<u8>> {
+    let chunks: Vec<&[u8]> = segments
+        .filter_map(|data| match data.strip_prefix(EXTENSION_SIGNATURE) {
+            Some(data) if data.len() >= 40 && &data[..32] == guid_.as_bytes() => Some(&data[32..]),
+            _ => None,
+        })
+        .collect();
+    let first = chunks.first()?;
+    let length = u32::from_be_bytes([first[0], first[1], first[2], first[3]]) as usize;
+    // The declared length is untrusted, the chunks have to add up to it anyway
+    if length > chunks.iter().map(|data| data.len() - 8).sum() {
+        return None;
+    }
+
+    let mut extended = vec![0; length];
+    let mut filled = 0;
+    for data in chunks {
+        let offset = u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as usize;
+        let chunk = &data[8..];
+        extended
+            .get_mut(offset..offset.checked_add(chunk.len())?)?
+            .copy_from_slice(chunk);
+        filled += chunk.len();
+    }
+    Some(extended).filter(|extended| filled == extended.len() && guid(extended) == guid_)
+}
+
+/// Moves the properties of the extended packet into the main one
+///
+/// Properties are matched by namespace, so the two packets don't need to use the same prefixes,
+/// and the ones the main packet holds in any of its descriptions are kept.
+fn merge_extended(main: &[u8], extended: &[u8]) -> Result<Vec<u8>, XmpError> {
+    let mut xmpmeta = try_load_element(Cursor::new(main))?;
+    let extended = try_load_element(Cursor::new(extended))?;
+
+    let scope = meta::scope_of(&Default::default(), &xmpmeta);
+    let rdf = xmpmeta
+        .get_child_mut("RDF", RDF)
+        .otor(|| XmpErrorKind::ChildNotFound)?;
+    let scope = meta::scope_of(&scope, rdf);
+    for description in rdf.children_mut().filter(|e| e.is("Description", RDF)) {
+        let scope = meta::scope_of(&scope, description);
+        let item = XMPNOTE_HASEXTENDEDXMP;
+        if let Some(key) = meta::attr_key(description, &scope, item.namespace, item.name) {
+            let key = key.to_owned();
+            description.remove_attr(&key);
+        }
+    }
+
+    let holds = |rdf: &Element, namespace: &str, name: &str| {
+        rdf.children()
+            .filter(|e| e.is("Description", RDF))
+            .any(|description| {
+                let scope = meta::scope_of(&scope, description);
+                meta::attr(description, &scope, namespace, name).is_some()
+                    || description.has_child(name, namespace)
+            })
+    };
+    let extended_scope = meta::scope_of(&Default::default(), &extended);
+    let extended_rdf = extended
+        .get_child("RDF", RDF)
+        .otor(|| XmpErrorKind::ChildNotFound)?;
+    let extended_scope = meta::scope_of(&extended_scope, extended_rdf);
+    for extended_description in extended_rdf.children().filter(|e| e.is("Description", RDF)) {
+        let from = meta::scope_of(&extended_scope, extended_description);
+        for (key, value) in extended_description.attrs() {
+            let Some(name) = meta::attr_name(&from, key) else {
+                continue;
+            };
+            if name.namespace == RDF || holds(rdf, &name.namespace, &name.name) {
+                continue;
+            }
+            let item = XmpItem {
+                name: &name.name,
+                namespace: &name.namespace,
+                namespace_short: key.split_once(':').map_or("ns", |(prefix, _)| prefix),
+            };
+            let description = rdf
+                .get_child_mut("Description", RDF)
+                .otor(|| XmpErrorKind::ChildNotFound)?;
+            let scope = meta::scope_of(&scope, description);
+            set_item(description, &scope, item, value)?;
+        }
+        for child in extended_description.children() {
+            if holds(rdf, &child.ns(), child.name()) {
+                continue;
+            }
+            let description = rdf
+                .get_child_mut("Description", RDF)
+                .otor(|| XmpErrorKind::ChildNotFound)?;
+            // Declare the prefixes the child is written with, unless they are bound already
+            let into = meta::scope_of(&scope, description);
+            for (prefix, namespace) in &from {
+                if prefix.is_some() && !into.contains_key(prefix) {
+                    description
+                        .prefixes
+                        .insert(prefix.clone(), namespace.clone());
+                }
+            }
+            description.append_child(child.clone());
+        }
+    }
+    serialize(&xmpmeta)
+}
+
+/// Splits a packet which doesn't fit in the standard segment into the main and extended packets
+///
+/// The largest properties are moved to the extended packet until the main one fits. The main
+/// packet points to the extended one with `xmpNote:HasExtendedXMP`.
+fn split_extended(xml: Vec<u8>) -> Result<(Vec<u8>, Option<Vec<u8>>), XmpError> {
+    if xml.len() <= MAX_PACKET {
+        return Ok((xml, None));
+    }
+    let mut xmpmeta = try_load_element(Cursor::new(&xml))?;
+    let declared = |e: &Element| {
+        e.prefixes
+            .declared_prefixes()
+            .iter()
+            .filter_map(|(prefix, ns)| Some((prefix.clone()?, ns.clone())))
+            .filter(|(prefix, _)| prefix != "x" && prefix != "rdf")
+            .collect::<Vec<_>>()
+    };
+    let rdf = xmpmeta
+        .get_child("RDF", RDF)
+        .otor(|| XmpErrorKind::ChildNotFound)?;
+    let outer = [declared(&xmpmeta), declared(rdf)].concat();
+    let descriptions = rdf.children().filter(|e| e.is("Description", RDF));
+
+    // Properties of every description are candidates, along with the prefixes in their scope
+    let mut prefixes = Vec::new();
+    let mut properties = Vec::new();
+    for (index, description) in descriptions.enumerate() {
+        prefixes.push([outer.clone(), declared(description)].concat());
+        for child in description.children() {
+            let size = serialize(child)?.len();
+            properties.push((size, index, child.name().to_owned(), child.ns()));
+        }
+    }
+    properties.sort_by_key(|property| std::cmp::Reverse(property.0));
+
+    let description = description_mut(&mut xmpmeta)?;
+    description.add_prefix("xmpNote", XMPNOTE)?;
+    // Reserve the space of the GUID while measuring
+    description.set_attr(XMPNOTE_HASEXTENDEDXMP.attr_name(), "0".repeat(32));
+    let mut moved = vec![Vec::new(); prefixes.len()];
+    for (_, index, name, ns) in properties {
+        if serialize(&xmpmeta)?.len() <= MAX_PACKET {
+            break;
+        }
+        let description = xmpmeta
+            .get_child_mut("RDF", RDF)
+            .and_then(|rdf| {
+                rdf.children_mut()
+                    .filter(|e| e.is("Description", RDF))
+                    .nth(index)
+            })
+            .otor(|| XmpErrorKind::ChildNotFound)?;
+        moved[index].extend(description.remove_child(&name, ns.as_str()));
+    }
+    if moved.iter().all(Vec::is_empty) || serialize(&xmpmeta)?.len() > MAX_PACKET {
+        return Err(XmpErrorKind::PacketTooLarge.into());
+    }
+
+    let mut extended_rdf = Element::builder("RDF", RDF)
+        .prefix(Some("rdf".into()), RDF)?
+        .build();
+    for (moved, prefixes) in moved.into_iter().zip(prefixes) {
+        if moved.is_empty() {
+            continue;
+        }
+        let mut extended_description = Element::builder("Description", RDF)
+            .attr("rdf:about", "")
+            .append_all(moved)
+            .build();
+        extended_description.add_prefixes(prefixes)?;
+        extended_rdf.append_child(extended_description);
+    }
+    let extended = Element::builder("xmpmeta", X)
+        .prefix(Some("x".into()), X)?
+        .append(extended_rdf)
+        .build();
+    let extended = serialize(&extended)?;
+
+    description_mut(&mut xmpmeta)?.set_attr(XMPNOTE_HASEXTENDEDXMP.attr_name(), guid(&extended));
+    Ok((serialize(&xmpmeta)?, Some(extended)))
+}
+
+/// Splits the extended packet into the contents of the APP1 segments
+fn extension_segments(extended: &[u8]) -> Result<Vec<Segment>, XmpError> {
+    let guid = guid(extended);
+    let length = u32::try_from(extended.len()).map_err(|_| XmpErrorKind::PacketTooLarge)?;
+    Ok(extended
+        .chunks(MAX_EXTENSION_CHUNK)
+        .enumerate()
+        .map(|(index, chunk)| {
+            let offset = (index * MAX_EXTENSION_CHUNK) as u32;
+            let contents = [
+                EXTENSION_SIGNATURE,
+                guid.as_bytes(),
+                &length.to_be_bytes(),
+                &offset.to_be_bytes(),
+                chunk,
+            ]
+            .concat();
+            Segment::new(markers::APP1, contents)
+        })
+        .collect())
+}
+
+fn is_xmp_segment(segment: &Segment, signature: &[u8]) -> bool {
+    segment.marker == markers::APP1 && segment.contents.starts_with(signature)
+}
+
+/// Contents of the exif segment after its prefix
+fn exif_data(segments: &[Segment]) -> Option<&[u8]> {
+    segments
+        .iter()
+        .find(|segment| is_xmp_segment(segment, EXIF_DATA_PREFIX))
+        .map(|segment| &segment.contents[EXIF_DATA_PREFIX.len()..])
+}
+
+pub(crate) fn __jpeg_xml(segments: &[Segment]) -> Result<Vec<u8>, XmpError> {
+    // The standard segment is preferred over the xmp tag of the exif data
+    let mut main = segments
+        .iter()
+        .find(|segment| is_xmp_segment(segment, XMP_SIGNATURE))
+        .map(|segment| segment.contents[XMP_SIGNATURE.len()..].to_vec());
+    if let (None, Some(exif_data)) = (&main, exif_data(segments)) {
         let exifreader = exif::Reader::new();
         let exif = exifreader.read_raw(exif_data.to_vec())?;
         let __xmp_val = exif
             .get_field(Tag(Context::Exif, 700), In::PRIMARY)
             .map(|f| &f.value);
         if let Some(exif::Value::Undefined(data, _)) = __xmp_val {
-            return Ok(data.to_vec());
+            main = Some(data.to_vec());
         }
     }
-    let file = std::fs::File::open(path)?;
-    let bfr = BufReader::new(file);
-    let mut jfif = Reader::new(bfr)?;
+    let main = main.otor(|| XmpErrorKind::XMPMissing)?;
 
-    loop {
-        let segment = jfif.next_segment()?;
-        match &segment.kind {
-            SegmentKind::Eoi => break,
-            SegmentKind::App { nr: 0x1, data } => {
-                if data.starts_with(b"http://ns.adobe.com") {
-                    return Ok(data.to_vec());
-                }
-            }
-            _ => (),
-        }
+    let guid = try_load_element(Cursor::new(&main))
+        .and_then(|xmpmeta| try_find_item(&xmpmeta, XMPNOTE_HASEXTENDEDXMP));
+    let extended = guid.ok().and_then(|guid| {
+        let segments = segments
+            .iter()
+            .filter(|segment| segment.marker == markers::APP1)
+            .map(|segment| &segment.contents[..]);
+        extended_xmp(segments, &guid)
+    });
+    match extended {
+        Some(extended) => merge_extended(&main, &extended),
+        None => Ok(main),
     }
-
-    Err(XmpErrorKind::XMPMissing.into())
 }
 
-pub fn set_exif(jpeg: &mut img_parts::jpeg::Jpeg, exif: impl AsRef<[u8]>) {
-    use bytes::BufMut;
-    let exif_data = exif.as_ref();
-    // jpeg.set_exif(Some(exif_data.into()));
-    jpeg.segments_mut().retain(|segment| {
-        !(segment.marker() == img_parts::jpeg::markers::APP1
-            && segment.contents().starts_with(EXIF_DATA_PREFIX))
-    });
-    let mut contents = bytes::BytesMut::with_capacity(EXIF_DATA_PREFIX.len() + exif_data.len());
-    contents.put(EXIF_DATA_PREFIX.as_slice());
-    contents.put(exif_data);
+/// Index after the leading APP0 segments, JFIF requires its header to come first
+fn after_app0(segments: &[Segment]) -> usize {
+    segments
+        .iter()
+        .position(|segment| segment.marker != markers::APP0)
+        .unwrap_or(segments.len())
+}
 
-    let segment = img_parts::jpeg::JpegSegment::new_with_contents(
-        img_parts::jpeg::markers::APP1,
-        contents.freeze(),
-    );
-    jpeg.segments_mut().insert(0, segment);
+/// Replaces the exif segment in place, or adds it after the APP0 segments
+fn set_exif(segments: &mut Vec<Segment>, exif_data: &[u8]) {
+    let segment = Segment::new(markers::APP1, [EXIF_DATA_PREFIX, exif_data].concat());
+    match segments
+        .iter()
+        .position(|segment| is_xmp_segment(segment, EXIF_DATA_PREFIX))
+    {
+        Some(index) => segments[index] = segment,
+        None => segments.insert(after_app0(segments), segment),
+    }
 }
 
-impl UpdateResults {
-    pub fn update_jpg(
-        &self,
-        path: impl AsRef<Path>,
-        options: UpdateOptions,
-    ) -> Result<(), XmpError> {
-        let xml = __jpeg_load_xml(&path).unwrap_or_else(|_e| DEFAULT_XML.as_bytes().to_vec());
-        // let xml = self.update_xml(Cursor::new(xml))?;
-        let xml = self.update_xml(Cursor::new(xml), options)?;
-        let data = std::fs::read(&path)?;
-        // let mut jpeg = img_parts::jpeg::Jpeg::from_bytes(std::fs::read(&path)?.into())?;
-        let mut jpeg = img_parts::jpeg::Jpeg::from_bytes(data.into())
-            .map_err(|e| XmpError::from(e).with_name(path.as_ref()))?;
-        // Do not overwrite the existing exif data
-        let mut exifwriter = exif::experimental::Writer::new();
-
-        let exif = jpeg.exif().and_then(|exif_data| {
-            let exifreader = exif::Reader::new();
-            exifreader.read_raw(exif_data.to_vec()).ok()
-        });
+/// Rewrites the xmp in the header segments, all other segments are kept as they are
+fn __jpeg_write_xml(
+    segments: &mut Vec<Segment>,
+    xml: Vec<u8>,
+    mode: JpegXmp,
+) -> Result<(), XmpError> {
+    // Do not overwrite the existing exif data
+    let mut exifwriter = exif::experimental::Writer::new();
 
-        let exif_xml_tag = Field {
-            tag: Tag(Context::Exif, 700),
-            ifd_num: In::PRIMARY,
-            value: Value::Undefined(xml, 0),
-        };
+    let exif = exif_data(segments).and_then(|exif_data| {
+        let exifreader = exif::Reader::new();
+        exifreader.read_raw(exif_data.to_vec()).ok()
+    });
 
-        let mut exif_data = std::io::Cursor::new(Vec::new());
+    // Packets too large for a single segment go to the standard and extension segments
+    let (xml, extended) = match mode {
+        JpegXmp::Exif => (xml, None),
+        JpegXmp::Standard | JpegXmp::Both => split_extended(xml)?,
+    };
+    let (exif_xml, standard_xml) = match mode {
+        JpegXmp::Standard => (None, Some(xml)),
+        JpegXmp::Exif => (Some(xml), None),
+        JpegXmp::Both => (Some(xml.clone()), Some(xml)),
+    };
 
-        if let Some(exif) = exif {
+    // Only rewrite the exif data to add the packet or drop a stale one
+    let xmp_tag = Tag(Context::Exif, 700);
+    let has_xmp_tag = exif
+        .as_ref()
+        .is_some_and(|exif| exif.get_field(xmp_tag, In::PRIMARY).is_some());
+    let has_other_tags = exif
+        .as_ref()
+        .is_some_and(|exif| exif.fields().any(|field| field.tag != xmp_tag));
+    if exif_xml.is_none() && has_xmp_tag && !has_other_tags {
+        // The packet was the only thing in the exif data
+        segments.retain(|segment| !is_xmp_segment(segment, EXIF_DATA_PREFIX));
+    } else if exif_xml.is_some() || has_xmp_tag {
+        let mut exif_data = std::io::Cursor::new(Vec::new());
+        if let Some(exif) = &exif {
             let mut insert: HashSet<Tag> = exif.fields().map(|f| f.tag).collect();
+            insert.remove(&xmp_tag);
             for field in exif.fields() {
                 if insert.contains(&field.tag) {
                     exifwriter.push_field(field);
                     insert.remove(&field.tag);
                 }
             }
-            exifwriter.push_field(&exif_xml_tag);
-            exifwriter
-                .write(&mut exif_data, false)
-                .map_err(|e| XmpError::from(e).with_name(path.as_ref()))?;
-        } else {
-            exifwriter.push_field(&exif_xml_tag);
-            exifwriter
-                .write(&mut exif_data, false)
-                .map_err(|e| XmpError::from(e).with_name(path.as_ref()))?;
         }
+        let exif_xml_tag = exif_xml.map(|xml| Field {
+            tag: xmp_tag,
+            ifd_num: In::PRIMARY,
+            value: Value::Undefined(xml, 0),
+        });
+        if let Some(exif_xml_tag) = &exif_xml_tag {
+            exifwriter.push_field(exif_xml_tag);
+        }
+        exifwriter.write(&mut exif_data, false)?;
 
         let exif_data = exif_data.into_inner();
-        jpeg.remove_segments_by_marker(0xE0);
-        // NOTE: The default set_exif inserts the exif header at the 3rd jpeg segment
-        // While it should be in the first segment for our case
-        // jpeg.set_exif(Some(exif_data.into()));
-        //
-        set_exif(&mut jpeg, exif_data);
-
-        let temp = path.as_ref().with_extension("temp");
-        let mut bfw = BufWriter::new(std::fs::File::create(&temp)?);
-
-        // This might cause panics
-        jpeg.encoder()
-            .write_to(&mut bfw)
-            .map_err(|e| XmpError::from(e).with_name(path.as_ref()))?;
-        bfw.flush()?;
-        std::fs::rename(temp, path)?;
+        if exif_data.len() + EXIF_DATA_PREFIX.len() > MAX_SEGMENT {
+            return Err(XmpErrorKind::PacketTooLarge.into());
+        }
+        set_exif(segments, &exif_data);
+    }
 
+    segments.retain(|segment| !is_xmp_segment(segment, EXTENSION_SIGNATURE));
+    let standard = segments
+        .iter()
+        .position(|segment| is_xmp_segment(segment, XMP_SIGNATURE));
+    match (standard_xml, standard) {
+        (Some(xml), standard) => {
+            let segment = Segment::new(markers::APP1, [XMP_SIGNATURE, &xml].concat());
+            // Update the existing segment in place or add it after the exif one
+            let index = match standard {
+                Some(index) => {
+                    segments[index] = segment;
+                    index
+                }
+                None => {
+                    let index = segments
+                        .iter()
+                        .position(|segment| is_xmp_segment(segment, EXIF_DATA_PREFIX))
+                        .map_or_else(|| after_app0(segments), |index| index + 1);
+                    segments.insert(index, segment);
+                    index
+                }
+            };
+            if let Some(extended) = extended {
+                let extension = extension_segments(&extended)?;
+                segments.splice(index + 1..index + 1, extension);
+            }
+        }
+        (None, Some(index)) => {
+            segments.remove(index);
+        }
+        (None, None) => (),
+    }
+    Ok(())
+}
+
+pub(crate) struct JpegContainer;
+
+impl container::XmpContainer for JpegContainer {
+    fn name(&self) -> &str {
+        "jpeg"
+    }
+
+    fn detect(&self, path: &Path, magic: &[u8]) -> bool {
+        ImageType::from_sniffed(path, magic) == ImageType::Jpg
+    }
+
+    fn extract(&self, mut reader: &mut dyn container::ReadSeek) -> Result<Vec<u8>, XmpError> {
+        let segments = read_segments(&mut reader, |marker| marker == markers::APP1)?;
+        __jpeg_xml(&segments)
+    }
+
+    fn replace(
+        &self,
+        mut reader: &mut dyn container::ReadSeek,
+        mut writer: &mut dyn Write,
+        xml: &[u8],
+        options: &UpdateOptions,
+    ) -> Result<(), XmpError> {
+        let mut segments = read_header(&mut reader)?;
+        __jpeg_write_xml(&mut segments, xml.to_vec(), options.jpeg_xmp)?;
+        write_header(&mut writer, &segments)?;
+        std::io::copy(reader, writer)?;
+        Ok(())
+    }
+
+    /// Reuses the parsed header instead of reading it again for the write
+    fn apply(
+        &self,
+        results: &UpdateResults,
+        mut reader: &mut dyn container::ReadSeek,
+        mut writer: &mut dyn Write,
+        options: UpdateOptions,
+    ) -> Result<(), XmpError> {
+        results.apply_jpg(&mut reader, &mut writer, options)
+    }
+}
+
+impl UpdateResults {
+    pub fn update_jpg(
+        &self,
+        path: impl AsRef<Path>,
+        options: UpdateOptions,
+    ) -> Result<(), XmpError> {
+        let mut reader = BufReader::new(std::fs::File::open(&path)?);
+        replace_file(&path, |writer| self.apply_jpg(&mut reader, writer, options))
+    }
+
+    /// Rewrites the header segments and streams the entropy coded data through, so memory use
+    /// doesn't depend on the size of the image
+    pub fn apply_jpg<R: Read, W: Write>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+        options: UpdateOptions,
+    ) -> Result<(), XmpError> {
+        let mut segments = read_header(reader)?;
+
+        let xml = __jpeg_xml(&segments).unwrap_or_else(|_e| DEFAULT_XML.as_bytes().to_vec());
+        let mode = options.jpeg_xmp;
+        let xml = self.update_xml(Cursor::new(xml), options)?;
+        __jpeg_write_xml(&mut segments, xml, mode)?;
+
+        write_header(writer, &segments)?;
+        std::io::copy(reader, writer)?;
         Ok(())
     }
 }
 
 impl OptionalResults {
     pub fn load_jpg(path: impl AsRef<Path>) -> Result<Self, XmpError> {
-        let data_cursor = Cursor::new(__jpeg_load_xml(path)?);
-        Self::from_reader(data_cursor)
+        Self::read_jpg(&mut BufReader::new(std::fs::File::open(path)?))
+    }
+
+    /// Reads only the header of the file, stopping at the first SOS
+    pub fn read_jpg<R: Read>(reader: &mut R) -> Result<Self, XmpError> {
+        // Exif and xmp are both stored in APP1 segments
+        let segments = read_segments(reader, |marker| marker == markers::APP1)?;
+        Self::from_reader(Cursor::new(__jpeg_xml(&segments)?))
     }
 }
 
@@ -202,6 +666,7 @@ pub fn test_jpeg_exif_load_updated() {
         orientation: None,
         hierarchies: Some(vec!["Duplicates"].iter().map(ToString::to_string).collect()),
         offset: None,
+        sidecar_for_extension: None,
     };
     assert_eq!(r_2, e);
 
@@ -214,3 +679,295 @@ pub fn test_jpeg_exif_load_updated() {
     let r_3 = OptionalResults::load("assets/3.jpg").unwrap();
     assert_eq!(r_1, r_3);
 }
+
+#[cfg(test)]
+pub(crate) fn sample_jpeg_with(header: &[(u8, &[u8])]) -> Vec<u8> {
+    let mut data = vec![0xff, markers::SOI];
+    for (marker, contents) in header {
+        data.extend_from_slice(&[0xff, *marker]);
+        data.extend_from_slice(&((contents.len() + 2) as u16).to_be_bytes());
+        data.extend_from_slice(contents);
+    }
+    data.extend_from_slice(b"entropy coded data");
+    data.extend_from_slice(&[0xff, markers::EOI]);
+    data
+}
+
+#[cfg(test)]
+pub(crate) fn sample_jpeg() -> Vec<u8> {
+    sample_jpeg_with(&[
+        (markers::APP0, b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0"),
+        (markers::DQT, &[0; 65]),
+        (markers::SOS, &[1, 1, 0, 0, 0x3f, 0]),
+    ])
+}
+
+#[test]
+pub fn jpeg_extended_xmp() {
+    let dir = std::env::temp_dir().join("xmp_jpeg_extended_xmp");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("file.jpg");
+    std::fs::write(&path, sample_jpeg()).unwrap();
+
+    let subjects: Vec<String> = (0..5000).map(|i| format!("Subject {}", i)).collect();
+    let u = UpdateResults {
+        stars: Some(4),
+        subjects: Some(subjects.clone()),
+        ..Default::default()
+    };
+    u.update(&path).unwrap();
+
+    let segments = read_header(&mut std::fs::read(&path).unwrap().as_slice()).unwrap();
+    let standard = segments
+        .iter()
+        .find(|segment| is_xmp_segment(segment, XMP_SIGNATURE))
+        .unwrap();
+    assert!(standard.contents.len() <= MAX_SEGMENT);
+    let extension: Vec<_> = segments
+        .iter()
+        .filter(|segment| is_xmp_segment(segment, EXTENSION_SIGNATURE))
+        .collect();
+    assert!(extension.len() > 1);
+    assert!(extension.iter().all(|s| s.contents.len() <= MAX_SEGMENT));
+
+    let r = OptionalResults::load(&path).unwrap();
+    assert_eq!(r.stars, Some(4));
+    let mut loaded = r.subjects.unwrap();
+    loaded.sort();
+    let mut expected = subjects;
+    expected.sort();
+    assert_eq!(loaded, expected);
+
+    // Shrinking the packet drops the extension segments
+    let u = UpdateResults {
+        subjects: Some(vec![String::from("Selected")]),
+        ..Default::default()
+    };
+    let options = UpdateOptions {
+        overwrite: true,
+        ..Default::default()
+    };
+    u.write_to_with_options(&path, options).unwrap();
+    let segments = read_header(&mut std::fs::read(&path).unwrap().as_slice()).unwrap();
+    assert!(!segments
+        .iter()
+        .any(|segment| is_xmp_segment(segment, EXTENSION_SIGNATURE)));
+    let r = OptionalResults::load(&path).unwrap();
+    assert_eq!(r.stars, Some(4));
+    assert_eq!(r.subjects, Some(vec![String::from("Selected")]));
+}
+
+#[test]
+pub fn jpeg_merge_extended_prefixes() {
+    let main = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about="" xmlns:xap="http://ns.adobe.com/xap/1.0/" xap:Rating="3"/>
+  <rdf:Description rdf:about="" xmlns:note="http://ns.adobe.com/xmp/note/"
+    note:HasExtendedXMP="0123456789ABCDEF0123456789ABCDEF"/>
+ </rdf:RDF>
+</x:xmpmeta>"#;
+    let extended = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about="" xmlns:ns1="http://ns.adobe.com/xap/1.0/"
+    xmlns:dc="http://purl.org/dc/elements/1.1/" ns1:Rating="1" ns1:Label="Red">
+   <dc:subject><rdf:Bag><rdf:li>Moved</rdf:li></rdf:Bag></dc:subject>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>"#;
+    let merged = merge_extended(main.as_bytes(), extended.as_bytes()).unwrap();
+    let text = String::from_utf8(merged.clone()).unwrap();
+    assert!(!text.contains("HasExtendedXMP"));
+    assert_eq!(text.matches("Rating").count(), 1);
+    assert!(text.contains(r#"xap:Label="Red""#));
+
+    let r = OptionalResults::from_reader(Cursor::new(merged)).unwrap();
+    assert_eq!(r.stars, Some(3));
+    assert_eq!(r.colors.as_deref(), Some("Red"));
+    assert_eq!(r.subjects, Some(vec![String::from("Moved")]));
+}
+
+#[test]
+pub fn jpeg_split_extended_descriptions() {
+    let subjects: String = (0..5000)
+        .map(|i| format!("<rdf:li>Subject {}</rdf:li>", i))
+        .collect();
+    let xml = format!(
+        r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about="" xmlns:xmp="http://ns.adobe.com/xap/1.0/" xmp:Rating="2"/>
+  <rdf:Description rdf:about="" xmlns:subj="http://purl.org/dc/elements/1.1/">
+   <subj:subject><rdf:Bag>{}</rdf:Bag></subj:subject>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>"#,
+        subjects
+    );
+    let (main, extended) = split_extended(xml.into_bytes()).unwrap();
+    assert!(main.len() <= MAX_PACKET);
+    let extended = extended.unwrap();
+    assert!(!String::from_utf8_lossy(&main).contains("Subject 0"));
+
+    let merged = merge_extended(&main, &extended).unwrap();
+    let r = OptionalResults::from_reader(Cursor::new(merged)).unwrap();
+    assert_eq!(r.stars, Some(2));
+    assert_eq!(r.subjects.unwrap().len(), 5000);
+}
+
+#[test]
+pub fn jpeg_extended_length() {
+    let xml = b"<x:xmpmeta/>";
+    let guid_ = guid(xml);
+    let segment = |length: u32, offset: u32, chunk: &[u8]| {
+        [
+            EXTENSION_SIGNATURE,
+            guid_.as_bytes(),
+            &length.to_be_bytes(),
+            &offset.to_be_bytes(),
+            chunk,
+        ]
+        .concat()
+    };
+    let (first, second) = xml.split_at(5);
+    let (first, second) = (segment(12, 0, first), segment(12, 5, second));
+    assert_eq!(
+        extended_xmp([&second[..], &first[..]].into_iter(), &guid_).as_deref(),
+        Some(&xml[..])
+    );
+
+    // A declared length the chunks don't cover is rejected before allocating it
+    let huge = segment(u32::MAX, 0, xml);
+    assert_eq!(extended_xmp([&huge[..]].into_iter(), &guid_), None);
+    let offset = segment(12, u32::MAX, xml);
+    assert_eq!(extended_xmp([&offset[..]].into_iter(), &guid_), None);
+}
+
+#[test]
+pub fn jpeg_xmp_modes() {
+    let dir = std::env::temp_dir().join("xmp_jpeg_xmp_modes");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("file.jpg");
+    std::fs::write(&path, sample_jpeg()).unwrap();
+
+    let locations = || {
+        let segments = read_header(&mut std::fs::read(&path).unwrap().as_slice()).unwrap();
+        let standard = segments
+            .iter()
+            .position(|segment| is_xmp_segment(segment, XMP_SIGNATURE));
+        let exif = exif_data(&segments).map(|exif_data| {
+            let exif = exif::Reader::new().read_raw(exif_data.to_vec()).unwrap();
+            exif.get_field(Tag(Context::Exif, 700), In::PRIMARY)
+                .is_some()
+        });
+        (standard, exif)
+    };
+
+    for (stars, mode, expected) in [
+        (1, JpegXmp::Exif, (None, Some(true))),
+        (2, JpegXmp::Both, (Some(2), Some(true))),
+        (3, JpegXmp::Standard, (Some(1), None)),
+        (4, JpegXmp::Standard, (Some(1), None)),
+    ] {
+        let u = UpdateResults {
+            stars: Some(stars),
+            ..Default::default()
+        };
+        u.write_to_with_options(&path, UpdateOptions::default().jpeg_xmp(mode))
+            .unwrap();
+        assert_eq!(locations(), expected);
+        assert_eq!(OptionalResults::load(&path).unwrap().stars, Some(stars));
+    }
+}
+
+#[test]
+pub fn jpeg_preserve_segments() {
+    let dir = std::env::temp_dir().join("xmp_jpeg_preserve_segments");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("file.jpg");
+    let header: [(u8, &[u8]); 8] = [
+        (markers::APP0, b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0"),
+        (markers::APP0, b"JFXX\0\x13thumbnail"),
+        (markers::APP2, b"ICC_PROFILE\0\x01\x01profile"),
+        (markers::APP2, b"MPF\0index"),
+        (markers::APP13, b"Photoshop 3.0\08BIM"),
+        (markers::COM, b"comment"),
+        (markers::DQT, &[0; 65]),
+        (markers::SOS, &[1, 1, 0, 0, 0x3f, 0]),
+    ];
+    std::fs::write(&path, sample_jpeg_with(&header)).unwrap();
+
+    for mode in [JpegXmp::Both, JpegXmp::Standard] {
+        let u = UpdateResults {
+            stars: Some(5),
+            ..Default::default()
+        };
+        u.write_to_with_options(&path, UpdateOptions::default().jpeg_xmp(mode))
+            .unwrap();
+
+        let data = std::fs::read(&path).unwrap();
+        let segments = read_header(&mut data.as_slice()).unwrap();
+        let others: Vec<(u8, &[u8])> = segments
+            .iter()
+            .filter(|segment| {
+                !is_xmp_segment(segment, XMP_SIGNATURE)
+                    && !is_xmp_segment(segment, EXIF_DATA_PREFIX)
+            })
+            .map(|segment| (segment.marker, &segment.contents[..]))
+            .collect();
+        assert_eq!(others[..], header[..]);
+        assert_eq!(segments[0].marker, markers::APP0);
+        assert!(data.ends_with(b"entropy coded data\xff\xd9"));
+        assert_eq!(OptionalResults::load(&path).unwrap().stars, Some(5));
+    }
+}
+
+#[test]
+pub fn jpeg_stream_entropy() {
+    let dir = std::env::temp_dir().join("xmp_jpeg_stream_entropy");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("file.jpg");
+    let mut data = sample_jpeg();
+    data.truncate(data.len() - b"entropy coded data\xff\xd9".len());
+    // Stuffed bytes and restart markers are copied through as is
+    let entropy: Vec<u8> = (0..1 << 20)
+        .flat_map(|i| match i % 4096 {
+            0 => vec![0xff, 0x00],
+            1 => vec![0xff, markers::RST0 + (i / 4096 % 8) as u8],
+            _ => vec![(i % 251) as u8],
+        })
+        .chain([0xff, markers::EOI])
+        .collect();
+    data.extend_from_slice(&entropy);
+    std::fs::write(&path, &data).unwrap();
+
+    let u = UpdateResults {
+        colors: Some(String::from("Purple")),
+        ..Default::default()
+    };
+    u.update(&path).unwrap();
+    let updated = std::fs::read(&path).unwrap();
+    let mut reader = updated.as_slice();
+    let segments = read_header(&mut reader).unwrap();
+    assert_eq!(segments.last().unwrap().marker, markers::SOS);
+    assert_eq!(reader, entropy);
+    assert_eq!(
+        OptionalResults::load(&path).unwrap().colors.as_deref(),
+        Some("Purple")
+    );
+}
+
+#[test]
+pub fn jpeg_apply_to_bytes() {
+    let original = sample_jpeg();
+    let u = UpdateResults {
+        stars: Some(5),
+        colors: Some(String::from("Green")),
+        ..Default::default()
+    };
+    let data = u.apply_to_bytes(ImageType::Jpg, &original).unwrap();
+    assert!(data.ends_with(b"entropy coded data\xff\xd9"));
+
+    let r = OptionalResults::from_bytes(ImageType::Jpg, &data).unwrap();
+    assert_eq!(r.stars, Some(5));
+    assert_eq!(r.colors.as_deref(), Some("Green"));
+    assert!(OptionalResults::from_bytes(ImageType::Jpg, &original).is_err());
+}
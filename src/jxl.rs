@@ -0,0 +1,254 @@
+use crate::isobmff::{encode_box, read_boxes, BoxHeader};
+use crate::*;
+use std::io::Cursor;
+
+/// Signature of a bare codestream
+const CODESTREAM: [u8; 2] = [0xff, 0x0a];
+/// Signature box starting the container
+const SIGNATURE: [u8; 12] = [
+    0, 0, 0, 0x0c, b'J', b'X', b'L', b' ', 0x0d, 0x0a, 0x87, 0x0a,
+];
+const XML_BOX: [u8; 4] = *b"xml ";
+const BROTLI_BOX: [u8; 4] = *b"brob";
+/// Largest packet decompressed from a `brob` box, which could otherwise expand without bound
+const MAX_XML: u64 = 64 << 20;
+
+/// Whether the box holds an xmp packet, either raw or brotli compressed
+fn is_xml_box<R: Read + Seek>(header: &BoxHeader, reader: &mut R) -> Result<bool, XmpError> {
+    if header.kind == XML_BOX {
+        return Ok(true);
+    }
+    if header.kind != BROTLI_BOX {
+        return Ok(false);
+    }
+    // The compressed box starts with the type of the original box
+    let mut kind = [0; 4];
+    reader.seek(SeekFrom::Start(header.offset + header.header))?;
+    reader.read_exact(&mut kind)?;
+    Ok(kind == XML_BOX)
+}
+
+/// Reads the top level boxes after checking the file is a container and not a bare codestream
+fn container_boxes<R: Read + Seek>(reader: &mut R) -> Result<Option<Vec<BoxHeader>>, XmpError> {
+    let mut signature = [0; 12];
+    reader.seek(SeekFrom::Start(0))?;
+    let len = reader.take(12).read(&mut signature)?;
+    if signature[..len].starts_with(&CODESTREAM) {
+        return Ok(None);
+    }
+    if signature != SIGNATURE {
+        return Err(XmpErrorKind::InvalidJxl.into());
+    }
+    Ok(Some(read_boxes(reader)?))
+}
+
+/// Decompresses the body of a `brob` box, which starts with the type of the original box
+fn decompress(body: &[u8]) -> Result<Vec<u8>, XmpError> {
+    let compressed = body.get(4..).otor(|| XmpErrorKind::InvalidJxl)?;
+    let mut xml = Vec::new();
+    brotli_decompressor::Decompressor::new(compressed, 4096)
+        .take(MAX_XML + 1)
+        .read_to_end(&mut xml)?;
+    if xml.len() as u64 > MAX_XML {
+        return Err(XmpErrorKind::InvalidJxl.into());
+    }
+    Ok(xml)
+}
+
+pub(crate) fn __jxl_xml<R: Read + Seek>(reader: &mut R) -> Result<Vec<u8>, XmpError> {
+    // A bare codestream can't hold any metadata
+    let top = container_boxes(reader)?.otor(|| XmpErrorKind::XMPMissing)?;
+    for header in &top {
+        if !is_xml_box(header, reader)? {
+            continue;
+        }
+        let body = header.read(reader)?.split_off(header.header as usize);
+        if header.kind == XML_BOX {
+            return Ok(body);
+        }
+        return decompress(&body);
+    }
+    Err(XmpErrorKind::XMPMissing.into())
+}
+
+/// Writes the file with the packet in an uncompressed `xml ` box ahead of the codestream
+///
+/// Existing packets, compressed or not, are dropped. A bare codestream is wrapped in a container
+/// with a single `jxlc` box. The codestream is streamed through in both cases.
+pub(crate) fn __jxl_write_xml<R: Read + Seek, W: Write>(
+    reader: &mut R,
+    xml: &[u8],
+    writer: &mut W,
+) -> Result<(), XmpError> {
+    let top = match container_boxes(reader)? {
+        Some(top) => top,
+        None => {
+            writer.write_all(&SIGNATURE)?;
+            writer.write_all(&encode_box(b"ftyp", b"jxl \0\0\0\0jxl "))?;
+            writer.write_all(&encode_box(&XML_BOX, xml))?;
+            // The codestream box extends to the end of the file
+            writer.write_all(&[0, 0, 0, 0])?;
+            writer.write_all(b"jxlc")?;
+            reader.seek(SeekFrom::Start(0))?;
+            std::io::copy(reader, writer)?;
+            return Ok(());
+        }
+    };
+
+    let codestream = top
+        .iter()
+        .position(|b| &b.kind == b"jxlc" || &b.kind == b"jxlp")
+        .otor(|| XmpErrorKind::InvalidJxl)?;
+    for (i, header) in top.iter().enumerate() {
+        if i == codestream {
+            writer.write_all(&encode_box(&XML_BOX, xml))?;
+        }
+        if is_xml_box(header, reader)? {
+            continue;
+        }
+        reader.seek(SeekFrom::Start(header.offset))?;
+        let copied = std::io::copy(&mut reader.take(header.size), writer)?;
+        if copied != header.size {
+            return Err(XmpErrorKind::InvalidJxl.into());
+        }
+    }
+    Ok(())
+}
+
+impl UpdateResults {
+    pub fn update_jxl(
+        &self,
+        path: impl AsRef<Path>,
+        options: UpdateOptions,
+    ) -> Result<(), XmpError> {
+        let mut reader = BufReader::new(std::fs::File::open(&path)?);
+        replace_file(&path, |writer| self.apply_jxl(&mut reader, writer, options))
+    }
+
+    pub fn apply_jxl<R: Read + Seek, W: Write>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+        options: UpdateOptions,
+    ) -> Result<(), XmpError> {
+        let xml = __jxl_xml(reader).unwrap_or_else(|_e| DEFAULT_XML.as_bytes().to_vec());
+        let xml = self.update_xml(Cursor::new(xml), options)?;
+        __jxl_write_xml(reader, &xml, writer)
+    }
+}
+
+impl OptionalResults {
+    pub fn load_jxl(path: impl AsRef<Path>) -> Result<Self, XmpError> {
+        Self::read_jxl(&mut BufReader::new(std::fs::File::open(path)?))
+    }
+
+    pub fn read_jxl<R: Read + Seek>(reader: &mut R) -> Result<Self, XmpError> {
+        Self::from_reader(Cursor::new(__jxl_xml(reader)?))
+    }
+}
+
+/// Stands in for a codestream, only the signature matters
+#[cfg(test)]
+const SAMPLE_CODESTREAM: &[u8] = b"\xff\x0a\xfa\x1f\x00codestream";
+
+/// A container with a level box and the codestream split over two `jxlp` boxes
+#[cfg(test)]
+fn sample_jxl(xml: Option<&[u8]>) -> Vec<u8> {
+    let (first, second) = SAMPLE_CODESTREAM.split_at(6);
+    let mut out = [
+        &SIGNATURE[..],
+        &encode_box(b"ftyp", b"jxl \0\0\0\0jxl "),
+        &encode_box(b"jxll", &[5]),
+    ]
+    .concat();
+    if let Some(xml) = xml {
+        let mut compressed = Vec::new();
+        brotli::BrotliCompress(&mut Cursor::new(xml), &mut compressed, &Default::default())
+            .unwrap();
+        out.extend(encode_box(
+            &BROTLI_BOX,
+            &[&XML_BOX[..], &compressed].concat(),
+        ));
+    }
+    out.extend(encode_box(b"jxlp", &[&[0, 0, 0, 0][..], first].concat()));
+    out.extend(encode_box(
+        b"jxlp",
+        &[&[0x80, 0, 0, 1][..], second].concat(),
+    ));
+    out
+}
+
+#[test]
+pub fn jxl_read_brob() {
+    let data = sample_jxl(Some(DEFAULT_XML.as_bytes()));
+    assert_eq!(
+        __jxl_xml(&mut Cursor::new(&data)).unwrap(),
+        DEFAULT_XML.as_bytes()
+    );
+    assert!(__jxl_xml(&mut Cursor::new(sample_jxl(None))).is_err());
+    assert!(__jxl_xml(&mut Cursor::new(SAMPLE_CODESTREAM)).is_err());
+
+    // Too short to hold the type of the original box
+    assert!(decompress(b"xm").is_err());
+}
+
+#[test]
+pub fn jxl_write_xml() {
+    // The bare codestream ends up in a container
+    let mut data = Vec::new();
+    __jxl_write_xml(
+        &mut Cursor::new(SAMPLE_CODESTREAM),
+        DEFAULT_XML.as_bytes(),
+        &mut data,
+    )
+    .unwrap();
+    assert!(data.starts_with(&SIGNATURE));
+    assert!(data.ends_with(SAMPLE_CODESTREAM));
+    assert_eq!(
+        __jxl_xml(&mut Cursor::new(&data)).unwrap(),
+        DEFAULT_XML.as_bytes()
+    );
+
+    // The compressed packet is replaced by an uncompressed one before the codestream
+    let original = sample_jxl(Some(b"<x:xmpmeta/>"));
+    let mut data = Vec::new();
+    __jxl_write_xml(
+        &mut Cursor::new(&original),
+        DEFAULT_XML.as_bytes(),
+        &mut data,
+    )
+    .unwrap();
+    assert_eq!(
+        __jxl_xml(&mut Cursor::new(&data)).unwrap(),
+        DEFAULT_XML.as_bytes()
+    );
+    let kinds: Vec<_> = read_boxes(&mut Cursor::new(&data))
+        .unwrap()
+        .iter()
+        .map(|b| b.kind)
+        .collect();
+    assert_eq!(
+        kinds,
+        [*b"JXL ", *b"ftyp", *b"jxll", XML_BOX, *b"jxlp", *b"jxlp"]
+    );
+    let codestream = read_boxes(&mut Cursor::new(&original)).unwrap()[4].offset as usize;
+    assert!(data.ends_with(&original[codestream..]));
+}
+
+#[test]
+pub fn jxl_update() {
+    let dir = std::env::temp_dir().join("xmp_jxl_update");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("file.jxl");
+    std::fs::write(&path, SAMPLE_CODESTREAM).unwrap();
+
+    let u = UpdateResults {
+        stars: Some(2),
+        subjects: Some(vec![String::from("Archive")]),
+        ..Default::default()
+    };
+    u.update(&path).unwrap();
+    let r = OptionalResults::load(&path).unwrap();
+    assert_eq!(r.stars, Some(2));
+    assert_eq!(r.subjects, Some(vec![String::from("Archive")]));
+}
@@ -2,26 +2,12 @@ use chrono::{DateTime, NaiveDateTime, Utc};
 use minidom::Element;
 use std::clone::Clone;
 use std::collections::HashSet;
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
 use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 
-// General namespaces used in xmp
-// xmlns:xmp="http://ns.adobe.com/xap/1.0/"
-// xmlns:crd="http://ns.adobe.com/camera-raw-defaults/1.0/"
-// xmlns:photoshop="http://ns.adobe.com/photoshop/1.0/"
-// xmlns:stCamera="http://ns.adobe.com/photoshop/1.0/camera-profile"
-// xmlns:crlcp="http://ns.adobe.com/camera-raw-embedded-lens-profile/1.0/"
-// xmlns:tiff="http://ns.adobe.com/tiff/1.0/"
-// xmlns:exif="http://ns.adobe.com/exif/1.0/"
-// xmlns:aux="http://ns.adobe.com/exif/1.0/aux/"
-// xmlns:exifEX="http://cipa.jp/exif/1.0/"
-// xmlns:xmpMM="http://ns.adobe.com/xap/1.0/mm/"
-// xmlns:stEvt="http://ns.adobe.com/xap/1.0/sType/ResourceEvent#"
-// xmlns:dc="http://purl.org/dc/elements/1.1/"
-// xmlns:xmpRights="http://ns.adobe.com/xap/1.0/rights/"
-// xmlns:crs="http://ns.adobe.com/camera-raw-settings/1.0/"
-// xmlns:Iptc4xmpCore="http://iptc.org/std/Iptc4xmpCore/1.0/xmlns/"
+// General namespaces used in xmp, see namespace::NamespaceRegistry for their prefixes
+pub const X: &str = "adobe:ns:meta/";
 pub const RDF: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#";
 pub const DC: &str = "http://purl.org/dc/elements/1.1/";
 pub const TIFF: &str = "http://ns.adobe.com/tiff/1.0/";
@@ -29,6 +15,30 @@ pub const LR: &str = "http://ns.adobe.com/lightroom/1.0/";
 pub const XMP: &str = "http://ns.adobe.com/xap/1.0/";
 pub const EXIF: &str = "http://ns.adobe.com/exif/1.0/";
 pub const PHOTOSHOP: &str = "http://ns.adobe.com/photoshop/1.0/";
+pub const XMPNOTE: &str = "http://ns.adobe.com/xmp/note/";
+pub const XMP_MM: &str = "http://ns.adobe.com/xap/1.0/mm/";
+pub const XMP_RIGHTS: &str = "http://ns.adobe.com/xap/1.0/rights/";
+pub const XMP_BJ: &str = "http://ns.adobe.com/xap/1.0/bj/";
+pub const XMP_TPG: &str = "http://ns.adobe.com/xap/1.0/t/pg/";
+pub const XMP_DM: &str = "http://ns.adobe.com/xmp/1.0/DynamicMedia/";
+pub const XMP_G_IMG: &str = "http://ns.adobe.com/xap/1.0/g/img/";
+pub const XMP_IDQ: &str = "http://ns.adobe.com/xmp/Identifier/qual/1.0/";
+pub const ST_EVT: &str = "http://ns.adobe.com/xap/1.0/sType/ResourceEvent#";
+pub const ST_REF: &str = "http://ns.adobe.com/xap/1.0/sType/ResourceRef#";
+pub const ST_DIM: &str = "http://ns.adobe.com/xap/1.0/sType/Dimensions#";
+pub const ST_AREA: &str = "http://ns.adobe.com/xmp/sType/Area#";
+pub const ST_JOB: &str = "http://ns.adobe.com/xap/1.0/sType/Job#";
+pub const PDF: &str = "http://ns.adobe.com/pdf/1.3/";
+pub const EXIF_EX: &str = "http://cipa.jp/exif/1.0/";
+pub const AUX: &str = "http://ns.adobe.com/exif/1.0/aux/";
+pub const CRS: &str = "http://ns.adobe.com/camera-raw-settings/1.0/";
+pub const CRD: &str = "http://ns.adobe.com/camera-raw-defaults/1.0/";
+pub const IPTC_CORE: &str = "http://iptc.org/std/Iptc4xmpCore/1.0/xmlns/";
+pub const IPTC_EXT: &str = "http://iptc.org/std/Iptc4xmpExt/2008-02-29/";
+pub const PLUS: &str = "http://ns.useplus.org/ldf/xmp/1.0/";
+pub const MWG_RS: &str = "http://www.metadataworkinggroup.com/schemas/regions/";
+pub const MWG_KW: &str = "http://www.metadataworkinggroup.com/schemas/keywords/";
+pub const MWG_COLL: &str = "http://www.metadataworkinggroup.com/schemas/collections/";
 
 /// make_item!(EXIF, "DateTimeOriginal")
 /// expands to
@@ -37,14 +47,20 @@ pub const PHOTOSHOP: &str = "http://ns.adobe.com/photoshop/1.0/";
 ///     namespace: "http://ns.adobe.com/exif/1.0/",
 ///     namespace_short: "exif",
 /// };
+///
+/// The prefix is looked up in the standard namespaces of [`namespace::NamespaceRegistry`], other
+/// namespaces need it given as a third argument
 #[macro_export]
 macro_rules! make_item {
     ($ns:expr, $item:expr) => {
+        $crate::make_item!($ns, $item, $crate::namespace::standard_prefix($ns));
+    };
+    ($ns:expr, $item:expr, $prefix:expr) => {
         paste::paste! {
             pub const [<$ns:upper _ $item:upper>]: XmpItem = XmpItem {
                 name: $item,
                 namespace: $ns,
-                namespace_short: stringify!([<$ns:lower>]),
+                namespace_short: $prefix,
             };
         }
     };
@@ -56,6 +72,8 @@ make_item!(XMP, "CreateDate");
 make_item!(XMP, "Rating");
 make_item!(XMP, "Label");
 make_item!(PHOTOSHOP, "SidecarForExtension");
+make_item!(DC, "subject");
+make_item!(LR, "hierarchicalSubject");
 
 const XMP_EXT: [&str; 1] = ["xmp"];
 const RAW_EXT: [&str; 37] = [
@@ -63,9 +81,15 @@ const RAW_EXT: [&str; 37] = [
     "eip", "erf", "fff", "gpr", "mdc", "mef", "mos", "mrw", "nrw", "obm", "orf", "pef", "ptx",
     "pxn", "r3d", "raw", "rwl", "rw2", "rwz", "sr2", "srf", "srw", "x3f", "raf",
 ];
-const JPG_EXT: [&str; 4] = ["jpg", "jpeg", "avif", "hif"];
+const JPG_EXT: [&str; 2] = ["jpg", "jpeg"];
 const PNG_EXT: [&str; 1] = ["png"];
-const HEIF_EXT: [&str; 2] = ["heic", "heif"];
+const HEIF_EXT: [&str; 3] = ["heic", "heif", "hif"];
+const AVIF_EXT: [&str; 1] = ["avif"];
+const WEBP_EXT: [&str; 1] = ["webp"];
+const GIF_EXT: [&str; 1] = ["gif"];
+const MP4_EXT: [&str; 3] = ["mp4", "mov", "m4v"];
+const PSD_EXT: [&str; 2] = ["psd", "psb"];
+const JXL_EXT: [&str; 1] = ["jxl"];
 const TIFF_EXT: [&str; 2] = ["tiff", "tif"];
 
 #[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
@@ -92,12 +116,38 @@ extern crate derive_builder;
 #[cfg(feature = "jpeg")]
 mod jpg;
 
-#[cfg(feature = "raw")]
 mod raw;
 
 #[cfg(feature = "png")]
 mod png;
 
+// The tiff and isobmff parsers are always built since the raw backend uses them
+#[cfg_attr(not(feature = "tiff"), allow(dead_code))]
+mod tiff;
+
+#[cfg(feature = "avif")]
+mod avif;
+pub mod container;
+#[cfg(feature = "gif")]
+mod gif;
+#[cfg(feature = "heif")]
+mod heif;
+#[cfg_attr(not(any(feature = "heif", feature = "avif")), allow(dead_code))]
+mod isobmff;
+#[cfg(feature = "jxl")]
+mod jxl;
+mod magic;
+pub use magic::Mismatch;
+pub mod meta;
+#[cfg(feature = "mp4")]
+mod mp4;
+pub mod namespace;
+pub mod packet;
+#[cfg(feature = "psd")]
+mod psd;
+#[cfg(feature = "webp")]
+mod webp;
+
 pub mod time;
 mod xml;
 
@@ -110,7 +160,7 @@ mod traits;
 use traits::*;
 
 #[non_exhaustive]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ImageType {
     Raw,
     Xmp,
@@ -118,6 +168,12 @@ pub enum ImageType {
     Png,
     Tiff,
     Heif,
+    Avif,
+    WebP,
+    Gif,
+    Mp4,
+    Psd,
+    Jxl,
     Others,
 }
 
@@ -140,24 +196,94 @@ impl ImageType {
                     Self::Tiff
                 } else if HEIF_EXT.contains(&ext.as_str()) {
                     Self::Heif
+                } else if AVIF_EXT.contains(&ext.as_str()) {
+                    Self::Avif
+                } else if WEBP_EXT.contains(&ext.as_str()) {
+                    Self::WebP
+                } else if GIF_EXT.contains(&ext.as_str()) {
+                    Self::Gif
+                } else if MP4_EXT.contains(&ext.as_str()) {
+                    Self::Mp4
+                } else if PSD_EXT.contains(&ext.as_str()) {
+                    Self::Psd
+                } else if JXL_EXT.contains(&ext.as_str()) {
+                    Self::Jxl
                 } else {
                     Self::Others
                 }
             })
             .unwrap_or(Self::Others)
     }
+}
 
-    // #[cfg(feature = "magic")]
-    // pub fn from_magic(p: impl AsRef<Path>) -> Self {
-    //     use magic::{Cookie, CookieFlags};
-    //     let cookie = magic::Cookie::open(CookieFlags::default()).unwrap();
-    // }
+/// Where the xmp packet of jpeg files is written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum JpegXmp {
+    /// The APP1 segment starting with `http://ns.adobe.com/xap/1.0/` that other readers expect
+    #[default]
+    Standard,
+    /// Tag 700 of the exif data, as written by earlier versions
+    Exif,
+    /// Both the standard segment and the exif tag
+    Both,
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone)]
 pub struct UpdateOptions {
     indent: Option<(u8, usize)>,
     overwrite: bool,
+    /// Create a new sidecar for raw files which don't have one yet
+    create_sidecar: bool,
+    /// Write to tag 700 of IFD0 of dng files instead of the sidecar
+    embed_dng: bool,
+    jpeg_xmp: JpegXmp,
+    /// Move the properties of all `rdf:Description` elements into the first one
+    merge_descriptions: bool,
+}
+
+impl Default for UpdateOptions {
+    fn default() -> Self {
+        Self {
+            indent: None,
+            overwrite: false,
+            create_sidecar: true,
+            embed_dng: false,
+            jpeg_xmp: JpegXmp::Standard,
+            merge_descriptions: false,
+        }
+    }
+}
+
+impl UpdateOptions {
+    /// When disabled, writing to a raw file without a neighbouring `.xmp` fails with
+    /// [`XmpErrorKind::InvalidFileType`] instead of creating the sidecar
+    pub fn create_sidecar(mut self, create_sidecar: bool) -> Self {
+        self.create_sidecar = create_sidecar;
+        self
+    }
+
+    /// When enabled, dng files are updated in place like Adobe tools expect instead of through a
+    /// sidecar. The raw image data is left untouched. Requires the `tiff` feature
+    pub fn embed_dng(mut self, embed_dng: bool) -> Self {
+        self.embed_dng = embed_dng;
+        self
+    }
+
+    /// Selects whether jpeg files get the standard xmp segment, the exif tag or both. Packets
+    /// larger than a segment are split into Extended XMP which needs the standard segment, so
+    /// they fail with [`XmpErrorKind::PacketTooLarge`] when only the exif tag is written
+    pub fn jpeg_xmp(mut self, jpeg_xmp: JpegXmp) -> Self {
+        self.jpeg_xmp = jpeg_xmp;
+        self
+    }
+
+    /// Packets written by some tools split their properties over several `rdf:Description`
+    /// elements. They are always read and updated as one, when enabled they are also merged into
+    /// a single description on save
+    pub fn merge_descriptions(mut self, merge_descriptions: bool) -> Self {
+        self.merge_descriptions = merge_descriptions;
+        self
+    }
 }
 
 #[derive(Debug, Default, Builder, PartialEq)]
@@ -181,18 +307,27 @@ impl UpdateResults {
     {
         let mut xmpmeta = try_load_element(reader)?;
 
-        let description = xmpmeta
+        let scope = meta::scope_of(&Default::default(), &xmpmeta);
+        let rdf = xmpmeta
             .get_child_mut("RDF", RDF)
-            .and_then(|rdf| rdf.get_child_mut("Description", RDF))
             .otor(|| XmpErrorKind::ChildNotFound)?;
-
-        description.add_prefixes([("xmp", XMP), ("exif", EXIF)])?;
+        let scope = meta::scope_of(&scope, rdf);
+        if options.merge_descriptions {
+            merge_descriptions(rdf, &scope);
+        }
 
         if let Some(stars) = self.stars {
-            description.set_attr("xmp:Rating", stars)
+            let (description, scope) = description_for(rdf, &scope, XMP_RATING)?;
+            set_item(description, &scope, XMP_RATING, stars)?;
         }
         if let Some(colors) = &self.colors {
-            description.set_attr("xmp:Label", colors)
+            let (description, scope) = description_for(rdf, &scope, XMP_LABEL)?;
+            set_item(description, &scope, XMP_LABEL, colors)?;
+        }
+        if let Some(ext) = &self.sidecar_for_extension {
+            let item = PHOTOSHOP_SIDECARFOREXTENSION;
+            let (description, scope) = description_for(rdf, &scope, item)?;
+            set_item(description, &scope, item, ext)?;
         }
 
         if let Some(datetime) = self.datetime {
@@ -207,13 +342,17 @@ impl UpdateResults {
                 DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(datetime, 0), Utc)
                     .with_timezone(&offset);
 
-            if description.has_child("DateTimeOriginal", EXIF) {
-                description.remove_child("DateTimeOriginal", EXIF);
-            }
-            description.set_attr("exif:DateTimeOriginal", &datetime.to_rfc3339());
+            let (description, scope) = description_for(rdf, &scope, EXIF_DATETIMEORIGINAL)?;
+            set_item(
+                description,
+                &scope,
+                EXIF_DATETIMEORIGINAL,
+                datetime.to_rfc3339(),
+            )?;
         }
 
         if let Some(ref subjects) = self.subjects {
+            let (description, _) = description_for(rdf, &scope, DC_SUBJECT)?;
             let subjects: HashSet<String> = subjects.iter().cloned().collect();
             if let Some(bag) = description
                 .get_child_mut("subject", DC)
@@ -251,6 +390,7 @@ impl UpdateResults {
             }
         }
         if let Some(ref hierarchies) = self.hierarchies {
+            let (description, _) = description_for(rdf, &scope, LR_HIERARCHICALSUBJECT)?;
             let hierarchies: HashSet<String> = hierarchies.iter().cloned().collect();
             if let Some(bag) = description
                 .get_child_mut("hierarchicalSubject", LR)
@@ -307,10 +447,7 @@ impl UpdateResults {
         // let mut reader = quick_xml::Reader::from_reader(bytes);
         // let xmpmeta: Element = Element::from_reader(&mut reader)?;
         let xmpmeta: Element = try_load_element(reader)?;
-        let description = xmpmeta
-            .get_child("RDF", RDF)
-            .and_then(|rdf| rdf.get_child("Description", RDF))
-            .otor(|| XmpErrorKind::ChildNotFound)?;
+        let descriptions = try_get_descriptions(&xmpmeta)?;
 
         let mut results_builder = UpdateResultsBuilder::default();
         results_builder.colors(None);
@@ -322,7 +459,7 @@ impl UpdateResults {
         results_builder.offset(None);
         results_builder.sidecar_for_extension(None);
 
-        if let Ok(v) = try_get_item(description, EXIF_DATETIMEORIGINAL) {
+        if let Ok(v) = try_find_item(&xmpmeta, EXIF_DATETIMEORIGINAL) {
             // results_builder.datetime(crate::time::timestamp(&v.text()));
             let t = crate::time::timestamp_offset(&v);
 
@@ -330,11 +467,11 @@ impl UpdateResults {
             results_builder.offset(t.and_then(|d| d.1));
         }
 
-        if let Ok(v) = try_get_item(description, PHOTOSHOP_SIDECARFOREXTENSION) {
+        if let Ok(v) = try_find_item(&xmpmeta, PHOTOSHOP_SIDECARFOREXTENSION) {
             results_builder.sidecar_for_extension(Some(v));
         }
 
-        if let Ok(v) = try_get_item(description, XMP_CREATEDATE) {
+        if let Ok(v) = try_find_item(&xmpmeta, XMP_CREATEDATE) {
             let datetime = crate::time::timestamp_offset(&v);
             if datetime.is_some()
                 && results_builder
@@ -346,32 +483,35 @@ impl UpdateResults {
                 results_builder.offset(datetime.and_then(|d| d.1));
             }
         }
-        if let Ok(v) = try_get_item(description, XMP_RATING) {
+        if let Ok(v) = try_find_item(&xmpmeta, XMP_RATING) {
             results_builder.stars(v.parse().ok());
         }
-        if let Ok(v) = try_get_item(description, XMP_LABEL) {
+        if let Ok(v) = try_find_item(&xmpmeta, XMP_LABEL) {
             results_builder.colors(Some(v));
         }
 
-        if let Ok(v) = try_get_item(description, TIFF_ORIENTATION) {
+        if let Ok(v) = try_find_item(&xmpmeta, TIFF_ORIENTATION) {
             results_builder.orientation(v.parse().ok());
         }
-        let subjects = description
-            .get_child("subject", DC)
+        let subjects = descriptions
+            .iter()
+            .find_map(|description| description.get_child("subject", DC))
             .and_then(|subject| subject.get_child("Bag", RDF))
             .map(|bag| bag.children().map(|li| li.text()).collect::<Vec<String>>());
 
         results_builder.subjects(subjects);
 
-        if let Some(o) = description
-            .get_child("Orientation", TIFF)
+        if let Some(o) = descriptions
+            .iter()
+            .find_map(|description| description.get_child("Orientation", TIFF))
             .and_then(|o| o.text().parse().ok())
         {
             results_builder.orientation(Some(o));
         };
 
-        let hierarchies = description
-            .get_child("hierarchicalSubject", LR)
+        let hierarchies = descriptions
+            .iter()
+            .find_map(|description| description.get_child("hierarchicalSubject", LR))
             .and_then(|hierarchies| hierarchies.get_child("Bag", RDF))
             .map(|bag| bag.children().map(|li| li.text()).collect::<Vec<String>>());
         results_builder.hierarchies(hierarchies);
@@ -390,19 +530,50 @@ impl UpdateResults {
         path: impl AsRef<Path>,
         options: UpdateOptions,
     ) -> Result<(), XmpError> {
-        let img_type = ImageType::from_path(&path);
+        self.write_sniffed(&path, &magic::sniff(&path), options)
+    }
+
+    /// Like [`UpdateResults::write_to_with_options`], also returning whether the file was
+    /// updated as another type than its extension stands for
+    pub fn write_to_checked(
+        &self,
+        path: impl AsRef<Path>,
+        options: UpdateOptions,
+    ) -> Result<Option<Mismatch>, XmpError> {
+        let magic = magic::sniff(&path);
+        self.write_sniffed(&path, &magic, options)?;
+        Ok(ImageType::mismatch_sniffed(&path, &magic))
+    }
+
+    fn write_sniffed(
+        &self,
+        path: impl AsRef<Path>,
+        magic: &[u8],
+        options: UpdateOptions,
+    ) -> Result<(), XmpError> {
+        let img_type = ImageType::from_sniffed(&path, magic);
+        if let Some(container) = container::find(path.as_ref(), magic) {
+            return container.update(self, path.as_ref(), options);
+        }
         match img_type {
-            ImageType::Jpg => self.update_jpg(path, options),
-            ImageType::Png => self.update_png(path, options),
-            ImageType::Raw => {
-                if let Some(path) = exists_with_extension(&path, "xmp") {
-                    self.update_xmp(path, options)
-                } else {
-                    eprintln!("\x1b[31mRaw files not supported and xmp file not found\x1b[0m");
-                    Err(XmpError::from(XmpErrorKind::InvalidFileType))
-                }
-            }
-            ImageType::Xmp => self.update_xmp(path, options),
+            #[cfg(feature = "tiff")]
+            ImageType::Tiff => self.update_tiff(path, options),
+            #[cfg(feature = "heif")]
+            ImageType::Heif => self.update_heif(path, options),
+            #[cfg(feature = "avif")]
+            ImageType::Avif => self.update_avif(path, options),
+            #[cfg(feature = "webp")]
+            ImageType::WebP => self.update_webp(path, options),
+            #[cfg(feature = "gif")]
+            ImageType::Gif => self.update_gif(path, options),
+            #[cfg(feature = "mp4")]
+            ImageType::Mp4 => self.update_mp4(path, options),
+            #[cfg(feature = "psd")]
+            ImageType::Psd => self.update_psd(path, options),
+            #[cfg(feature = "jxl")]
+            ImageType::Jxl => self.update_jxl(path, options),
+            ImageType::Others => self.update_packet(path, options),
+            #[allow(unreachable_patterns)]
             _ => Err(XmpError::from(XmpErrorKind::InvalidFileType)),
         }
     }
@@ -410,6 +581,65 @@ impl UpdateResults {
     pub fn write_to(&self, path: impl AsRef<Path>) -> Result<(), XmpError> {
         self.write_to_with_options(path, Default::default())
     }
+
+    #[inline]
+    pub fn apply_to_bytes(&self, img_type: ImageType, data: &[u8]) -> Result<Vec<u8>, XmpError> {
+        self.apply_to_bytes_with_options(img_type, data, Default::default())
+    }
+
+    pub fn apply_to_bytes_with_options(
+        &self,
+        img_type: ImageType,
+        data: &[u8],
+        options: UpdateOptions,
+    ) -> Result<Vec<u8>, XmpError> {
+        let mut out = Vec::with_capacity(data.len() + 4096);
+        self.apply_to(img_type, &mut std::io::Cursor::new(data), &mut out, options)?;
+        Ok(out)
+    }
+
+    /// Copies the file in `reader` to `writer` with the update applied
+    ///
+    /// Raws are updated through their sidecar and aren't supported here, the sidecar itself can
+    /// be passed as [`ImageType::Xmp`]. Content handled by a [`container::XmpContainer`] goes to
+    /// it whatever `img_type` says.
+    pub fn apply_to<R: Read + Seek, W: Write>(
+        &self,
+        img_type: ImageType,
+        reader: &mut R,
+        writer: &mut W,
+        options: UpdateOptions,
+    ) -> Result<(), XmpError> {
+        let magic = magic::sniff_reader(reader)?;
+        if let Some(container) = container::find(Path::new(""), &magic) {
+            return container.apply(self, reader, writer, options);
+        }
+        match img_type {
+            #[cfg(feature = "jpeg")]
+            ImageType::Jpg => self.apply_jpg(reader, writer, options),
+            #[cfg(feature = "png")]
+            ImageType::Png => self.apply_png(reader, writer, options),
+            #[cfg(feature = "tiff")]
+            ImageType::Tiff => self.apply_tiff(reader, writer, options),
+            #[cfg(feature = "heif")]
+            ImageType::Heif => self.apply_heif(reader, writer, options),
+            #[cfg(feature = "avif")]
+            ImageType::Avif => self.apply_avif(reader, writer, options),
+            #[cfg(feature = "webp")]
+            ImageType::WebP => self.apply_webp(reader, writer, options),
+            #[cfg(feature = "gif")]
+            ImageType::Gif => self.apply_gif(reader, writer, options),
+            #[cfg(feature = "mp4")]
+            ImageType::Mp4 => self.apply_mp4(reader, writer, options),
+            #[cfg(feature = "psd")]
+            ImageType::Psd => self.apply_psd(reader, writer, options),
+            #[cfg(feature = "jxl")]
+            ImageType::Jxl => self.apply_jxl(reader, writer, options),
+            ImageType::Xmp => self.apply_xmp(reader, writer, options),
+            ImageType::Others => self.apply_packet(reader, writer, options),
+            _ => Err(XmpError::from(XmpErrorKind::InvalidFileType)),
+        }
+    }
 }
 
 pub type OptionalResults = UpdateResults;
@@ -417,36 +647,144 @@ pub type OptionalResults = UpdateResults;
 impl OptionalResults {
     #[inline]
     pub fn load(path: impl AsRef<Path>) -> Result<Self, XmpError> {
-        let img_type = ImageType::from_path(&path);
+        Self::load_sniffed(&path, &magic::sniff(&path))
+    }
+
+    /// Like [`OptionalResults::load`], also returning whether the file was loaded as another
+    /// type than its extension stands for
+    pub fn load_checked(path: impl AsRef<Path>) -> Result<(Self, Option<Mismatch>), XmpError> {
+        let magic = magic::sniff(&path);
+        let results = Self::load_sniffed(&path, &magic)?;
+        Ok((results, ImageType::mismatch_sniffed(&path, &magic)))
+    }
+
+    fn load_sniffed(path: impl AsRef<Path>, magic: &[u8]) -> Result<Self, XmpError> {
+        let img_type = ImageType::from_sniffed(&path, magic);
+        if let Some(container) = container::find(path.as_ref(), magic) {
+            return container.load(path.as_ref());
+        }
         match img_type {
-            ImageType::Xmp => OptionalResults::load_xmp(path),
-            ImageType::Jpg => OptionalResults::load_jpg(path),
-            ImageType::Png => OptionalResults::load_png(path),
-            ImageType::Raw => {
-                let raw_ext = path.as_ref().extension().and_then(OsStr::to_str);
-                if let Some(path) = exists_with_extension(&path, "xmp") {
-                    let xmp = OptionalResults::load_xmp(&path);
-                    if let Ok(UpdateResults {
-                        sidecar_for_extension: Some(ref sidecar_for_extension),
-                        ..
-                    }) = xmp
-                    {
-                        if let Some(ext) = raw_ext {
-                            if sidecar_for_extension.eq_ignore_ascii_case(ext) {
-                                return xmp;
-                            }
-                        }
-                    } else {
-                        return xmp;
-                    }
-                }
-                OptionalResults::load_raw(path)
-            }
+            #[cfg(feature = "tiff")]
+            ImageType::Tiff => OptionalResults::load_tiff(path),
+            #[cfg(feature = "heif")]
+            ImageType::Heif => OptionalResults::load_heif(path),
+            #[cfg(feature = "avif")]
+            ImageType::Avif => OptionalResults::load_avif(path),
+            #[cfg(feature = "webp")]
+            ImageType::WebP => OptionalResults::load_webp(path),
+            #[cfg(feature = "gif")]
+            ImageType::Gif => OptionalResults::load_gif(path),
+            #[cfg(feature = "mp4")]
+            ImageType::Mp4 => OptionalResults::load_mp4(path),
+            #[cfg(feature = "psd")]
+            ImageType::Psd => OptionalResults::load_psd(path),
+            #[cfg(feature = "jxl")]
+            ImageType::Jxl => OptionalResults::load_jxl(path),
+            ImageType::Others => OptionalResults::load_packet(path),
+            #[allow(unreachable_patterns)]
+            _ => Err(XmpError::from(XmpErrorKind::InvalidFileType)),
+        }
+    }
+
+    #[inline]
+    pub fn from_bytes(img_type: ImageType, data: &[u8]) -> Result<Self, XmpError> {
+        Self::read_from(img_type, &mut std::io::Cursor::new(data))
+    }
+
+    /// Loads the file in `reader`, raws are read without their sidecar
+    ///
+    /// Content handled by a [`container::XmpContainer`] goes to it whatever `img_type` says.
+    pub fn read_from<R: Read + Seek>(
+        img_type: ImageType,
+        reader: &mut R,
+    ) -> Result<Self, XmpError> {
+        let magic = magic::sniff_reader(reader)?;
+        if let Some(container) = container::find(Path::new(""), &magic) {
+            return container.read(reader);
+        }
+        match img_type {
+            ImageType::Xmp => OptionalResults::read_xmp(reader),
+            #[cfg(feature = "jpeg")]
+            ImageType::Jpg => OptionalResults::read_jpg(reader),
+            #[cfg(feature = "png")]
+            ImageType::Png => OptionalResults::read_png(reader),
+            #[cfg(feature = "tiff")]
+            ImageType::Tiff => OptionalResults::read_tiff(reader),
+            #[cfg(feature = "heif")]
+            ImageType::Heif => OptionalResults::read_heif(reader),
+            #[cfg(feature = "avif")]
+            ImageType::Avif => OptionalResults::read_avif(reader),
+            #[cfg(feature = "webp")]
+            ImageType::WebP => OptionalResults::read_webp(reader),
+            #[cfg(feature = "gif")]
+            ImageType::Gif => OptionalResults::read_gif(reader),
+            #[cfg(feature = "mp4")]
+            ImageType::Mp4 => OptionalResults::read_mp4(reader),
+            #[cfg(feature = "psd")]
+            ImageType::Psd => OptionalResults::read_psd(reader),
+            #[cfg(feature = "jxl")]
+            ImageType::Jxl => OptionalResults::read_jxl(reader),
+            ImageType::Raw => OptionalResults::read_raw(reader),
+            ImageType::Others => OptionalResults::read_packet(reader),
+            #[allow(unreachable_patterns)]
             _ => Err(XmpError::from(XmpErrorKind::InvalidFileType)),
         }
     }
 }
 
+#[inline]
+#[cfg(feature = "tiff")]
+fn is_dng(path: impl AsRef<Path>) -> bool {
+    path.as_ref()
+        .extension()
+        .and_then(OsStr::to_str)
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("dng"))
+}
+
+/// Writes the new content of `path` to a temporary file which then replaces it, so a failed
+/// update leaves the original untouched
+#[cfg_attr(
+    not(any(
+        feature = "jpeg",
+        feature = "png",
+        feature = "heif",
+        feature = "avif",
+        feature = "webp",
+        feature = "gif",
+        feature = "mp4",
+        feature = "psd",
+        feature = "jxl"
+    )),
+    allow(dead_code)
+)]
+fn replace_file(
+    path: impl AsRef<Path>,
+    write: impl FnOnce(&mut BufWriter<std::fs::File>) -> Result<(), XmpError>,
+) -> Result<(), XmpError> {
+    let path = path.as_ref();
+    // Unique per file and process, so concurrent updates of `a.jpg` and `a.png` don't collide
+    let file_name = path.file_name().otor(|| XmpErrorKind::InvalidFileType)?;
+    let mut temp_name = OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(format!(".{}.tmp", std::process::id()));
+    let temp = path.with_file_name(temp_name);
+
+    let mut bfw = BufWriter::new(std::fs::File::create(&temp)?);
+    let written = write(&mut bfw)
+        .and_then(|()| Ok(bfw.flush()?))
+        .and_then(|()| {
+            let permissions = std::fs::metadata(path)?.permissions();
+            Ok(bfw.get_ref().set_permissions(permissions)?)
+        });
+    drop(bfw);
+    if let Err(e) = written {
+        let _ = std::fs::remove_file(&temp);
+        return Err(e.with_name(path));
+    }
+    std::fs::rename(temp, path)?;
+    Ok(())
+}
+
 #[inline]
 fn exists_with_extension(path: impl AsRef<Path>, ext: impl AsRef<OsStr>) -> Option<PathBuf> {
     path.as_ref().with_extension(ext).canonicalize().ok()
@@ -505,8 +843,14 @@ where
     Ok(xmpmeta)
 }
 
+/// Looks `item` up by its namespace, prefixes being resolved through the declarations on
+/// `element`. [`try_find_item`] also takes those of the enclosing elements into account
 pub fn try_get_item(element: &Element, item: XmpItem) -> Result<String, XmpError> {
-    if let Some(s) = element.attr(&item.attr_name()) {
+    get_item(element, &meta::scope_of(&Default::default(), element), item)
+}
+
+fn get_item(element: &Element, scope: &meta::Scope, item: XmpItem) -> Result<String, XmpError> {
+    if let Some(s) = meta::attr(element, scope, item.namespace, item.name) {
         Ok(s.to_owned())
     } else if let Some(e) = element.get_child(item.name, item.namespace) {
         Ok(e.text())
@@ -524,3 +868,145 @@ pub fn try_get_description(element: &Element) -> Result<&Element, XmpError> {
         .and_then(|rdf| rdf.get_child("Description", RDF))
         .otor(|| XmpErrorKind::ChildNotFound)?)
 }
+
+/// The descriptions of the packet with the prefixes in scope of each of them
+fn scoped_descriptions(xmpmeta: &Element) -> Result<Vec<(&Element, meta::Scope)>, XmpError> {
+    let scope = meta::scope_of(&Default::default(), xmpmeta);
+    let rdf = xmpmeta
+        .get_child("RDF", RDF)
+        .otor(|| XmpErrorKind::ChildNotFound)?;
+    let scope = meta::scope_of(&scope, rdf);
+    let descriptions: Vec<_> = rdf
+        .children()
+        .filter(|child| child.is("Description", RDF))
+        .map(|description| (description, meta::scope_of(&scope, description)))
+        .collect();
+    if descriptions.is_empty() {
+        return Err(XmpErrorKind::ChildNotFound.into());
+    }
+    Ok(descriptions)
+}
+
+/// All `rdf:Description` elements of the packet, which together hold its properties
+pub fn try_get_descriptions(element: &Element) -> Result<Vec<&Element>, XmpError> {
+    Ok(scoped_descriptions(element)?
+        .into_iter()
+        .map(|(description, _)| description)
+        .collect())
+}
+
+/// Looks `item` up in every description of the `x:xmpmeta` element, the first one holding it
+/// wins
+pub fn try_find_item(xmpmeta: &Element, item: XmpItem) -> Result<String, XmpError> {
+    scoped_descriptions(xmpmeta)?
+        .iter()
+        .find_map(|(description, scope)| get_item(description, scope, item).ok())
+        .otor(|| XmpErrorKind::ChildNotFound)
+        .map_err(Into::into)
+}
+
+/// The description holding `item` with the prefixes in its scope, or the first one when none
+/// does, so updating a property stored in a later description doesn't add a second copy of it
+fn description_for<'a>(
+    rdf: &'a mut Element,
+    scope: &meta::Scope,
+    item: XmpItem,
+) -> Result<(&'a mut Element, meta::Scope), XmpError> {
+    let index = rdf
+        .children()
+        .filter(|child| child.is("Description", RDF))
+        .position(|description| {
+            let scope = meta::scope_of(scope, description);
+            meta::attr(description, &scope, item.namespace, item.name).is_some()
+                || description.has_child(item.name, item.namespace)
+        })
+        .unwrap_or(0);
+    let description = rdf
+        .children_mut()
+        .filter(|child| child.is("Description", RDF))
+        .nth(index)
+        .otor(|| XmpErrorKind::ChildNotFound)?;
+    let scope = meta::scope_of(scope, description);
+    Ok((description, scope))
+}
+
+/// Sets `item` as an attribute, keeping the prefix the packet already uses for its namespace and
+/// only declaring the registered prefix when there is none
+///
+/// The element form of the property is removed, leaving it in place would make it a duplicate.
+fn set_item<V: minidom::IntoAttributeValue>(
+    description: &mut Element,
+    scope: &meta::Scope,
+    item: XmpItem,
+    value: V,
+) -> Result<(), XmpError> {
+    while description
+        .remove_child(item.name, item.namespace)
+        .is_some()
+    {}
+    let key = if let Some(key) = meta::attr_key(description, scope, item.namespace, item.name) {
+        key.to_owned()
+    } else if let Some(prefix) = meta::prefix_of(scope, item.namespace) {
+        format!("{}:{}", prefix, item.name)
+    } else {
+        let prefix = namespace::assign(item.namespace, item.namespace_short, |p| {
+            scope.contains_key(&Some(p.to_owned()))
+        });
+        description.add_prefix(prefix.as_str(), item.namespace)?;
+        format!("{}:{}", prefix, item.name)
+    };
+    description.set_attr(key, value);
+    Ok(())
+}
+
+/// Moves the properties of every description into the first one, properties of earlier
+/// descriptions winning like they do when reading
+///
+/// Descriptions binding a prefix to another namespace than the first one are left as they are.
+fn merge_descriptions(rdf: &mut Element, scope: &meta::Scope) {
+    let mut descriptions = Vec::new();
+    while let Some(description) = rdf.remove_child("Description", RDF) {
+        descriptions.push(description);
+    }
+    let mut descriptions = descriptions.into_iter();
+    let Some(mut merged) = descriptions.next() else {
+        return;
+    };
+    let mut separate = Vec::new();
+    for description in descriptions {
+        let declared = description.prefixes.declared_prefixes();
+        let clashes = declared.iter().any(|(prefix, namespace)| {
+            merged
+                .prefixes
+                .get(prefix)
+                .is_some_and(|existing| existing != namespace)
+        });
+        if clashes {
+            separate.push(description);
+            continue;
+        }
+        for (prefix, namespace) in declared {
+            merged.prefixes.insert(prefix.clone(), namespace.clone());
+        }
+        let from = meta::scope_of(scope, &description);
+        let into = meta::scope_of(scope, &merged);
+        for (key, value) in description.attrs() {
+            let exists = match meta::attr_name(&from, key) {
+                Some(name) => meta::attr(&merged, &into, &name.namespace, &name.name).is_some(),
+                None => merged.attr(key).is_some(),
+            };
+            if !exists {
+                merged.set_attr(key, value);
+            }
+        }
+        for child in description.children() {
+            if !merged.has_child(child.name(), child.ns().as_str()) {
+                merged.append_child(child.clone());
+            }
+        }
+    }
+    rdf.append_child(merged);
+    for description in separate {
+        rdf.append_child(description);
+    }
+}
@@ -0,0 +1,238 @@
+use crate::*;
+
+/// Bytes read from the start of the file, enough for the `ftyp` box of most files
+const SNIFF_LEN: usize = 64;
+
+const HEIF_BRANDS: [&[u8; 4]; 9] = [
+    b"heic", b"heix", b"heim", b"heis", b"hevc", b"hevx", b"hevm", b"hevs", b"mif1",
+];
+const AVIF_BRANDS: [&[u8; 4]; 2] = [b"avif", b"avis"];
+const MP4_BRANDS: [&[u8; 4]; 12] = [
+    b"qt  ", b"isom", b"iso2", b"iso4", b"iso5", b"iso6", b"mp41", b"mp42", b"M4V ", b"avc1",
+    b"3gp4", b"3gp5",
+];
+
+/// Classifies an ISOBMFF file by its major brand, then by its compatible brands
+fn from_brands(ftyp: &[u8]) -> Option<ImageType> {
+    let major = ftyp.get(..4)?;
+    // The minor version sits between the major and the compatible brands
+    let compatible = ftyp.get(8..).unwrap_or_default().chunks_exact(4);
+    let classify = |brand: &[u8]| {
+        if AVIF_BRANDS.iter().any(|b| &b[..] == brand) {
+            Some(ImageType::Avif)
+        } else if HEIF_BRANDS.iter().any(|b| &b[..] == brand) {
+            Some(ImageType::Heif)
+        } else if MP4_BRANDS.iter().any(|b| &b[..] == brand) {
+            Some(ImageType::Mp4)
+        } else if brand == b"crx " {
+            Some(ImageType::Raw)
+        } else {
+            None
+        }
+    };
+    // mif1 only says the file is an image, avif files list it as well
+    let avif = |b: &[u8]| classify(b) == Some(ImageType::Avif);
+    if major == b"mif1" && compatible.clone().any(avif) {
+        return Some(ImageType::Avif);
+    }
+    classify(major).or_else(|| compatible.filter_map(classify).next())
+}
+
+/// Reads the bytes [`ImageType::detect`] looks at, nothing when the file can't be read
+pub(crate) fn sniff(p: impl AsRef<Path>) -> Vec<u8> {
+    let mut magic = Vec::with_capacity(SNIFF_LEN);
+    if let Ok(file) = std::fs::File::open(p) {
+        let _ = file.take(SNIFF_LEN as u64).read_to_end(&mut magic);
+    }
+    magic
+}
+
+/// Reads the bytes [`ImageType::detect`] looks at from `reader`, seeking back to where it was
+pub(crate) fn sniff_reader<R: Read + Seek>(reader: &mut R) -> Result<Vec<u8>, XmpError> {
+    let start = reader.stream_position()?;
+    let mut magic = Vec::with_capacity(SNIFF_LEN);
+    reader.take(SNIFF_LEN as u64).read_to_end(&mut magic)?;
+    reader.seek(SeekFrom::Start(start))?;
+    Ok(magic)
+}
+
+impl ImageType {
+    /// Detects the type from the signature at the start of `reader`, returning `None` for
+    /// content which isn't recognised
+    ///
+    /// Tiff based raws can't be told apart from plain tiffs by their signature alone and are
+    /// reported as [`ImageType::Tiff`], except for the ones with a signature of their own.
+    pub fn detect<R: Read>(reader: R) -> Result<Option<Self>, XmpError> {
+        let mut magic = Vec::with_capacity(SNIFF_LEN);
+        reader.take(SNIFF_LEN as u64).read_to_end(&mut magic)?;
+        let starts = |signature: &[u8]| magic.starts_with(signature);
+
+        let detected = if starts(&[0xff, 0xd8, 0xff]) {
+            Self::Jpg
+        } else if starts(b"\x89PNG\r\n\x1a\n") {
+            Self::Png
+        } else if starts(b"GIF87a") || starts(b"GIF89a") {
+            Self::Gif
+        } else if starts(b"RIFF") && magic.get(8..12) == Some(b"WEBP") {
+            Self::WebP
+        } else if starts(b"8BPS") {
+            Self::Psd
+        } else if starts(&[0xff, 0x0a]) || starts(b"\0\0\0\x0cJXL \r\n\x87\n") {
+            Self::Jxl
+        } else if starts(b"IIRO")
+            || starts(b"IIRS")
+            || starts(b"IIU\0")
+            || starts(b"FUJIFILMCCD-RAW")
+            || starts(b"FOVb")
+            || starts(b"\0MRM")
+            || (starts(b"II*\0") && magic.get(8..10) == Some(b"CR"))
+        {
+            Self::Raw
+        } else if starts(b"II*\0") || starts(b"MM\0*") || starts(b"II+\0") || starts(b"MM\0+") {
+            Self::Tiff
+        } else if magic.get(4..8) == Some(b"ftyp") {
+            let size = u32::from_be_bytes([magic[0], magic[1], magic[2], magic[3]]) as usize;
+            let ftyp = magic.get(8..size.clamp(8, magic.len())).unwrap_or_default();
+            match from_brands(ftyp) {
+                Some(detected) => detected,
+                None => return Ok(None),
+            }
+        } else {
+            let text = String::from_utf8_lossy(&magic);
+            let text = text.trim_start_matches('\u{feff}').trim_start();
+            if text.starts_with("<?xpacket") || text.starts_with("<x:xmpmeta") {
+                Self::Xmp
+            } else {
+                return Ok(None);
+            }
+        };
+        Ok(Some(detected))
+    }
+
+    /// Detects the type from the content of the file, falling back to the extension when the
+    /// file can't be read or its content isn't recognised
+    ///
+    /// The content wins when it disagrees with the extension, apart from tiff based raws whose
+    /// extension tells them apart from plain tiffs.
+    pub fn from_magic(p: impl AsRef<Path>) -> Self {
+        Self::from_sniffed(&p, &sniff(&p))
+    }
+
+    /// [`ImageType::from_magic`] with the first bytes of the file already read
+    pub fn from_sniffed(p: impl AsRef<Path>, magic: &[u8]) -> Self {
+        let from_path = Self::from_path(&p);
+        match (from_path, Self::detect(magic)) {
+            (Self::Raw, Ok(Some(Self::Tiff))) => Self::Raw,
+            (_, Ok(Some(detected))) => detected,
+            _ => from_path,
+        }
+    }
+
+    /// Whether the content of the file disagrees with its extension, in which case loading and
+    /// updating go by the content. [`OptionalResults::load_checked`] and
+    /// [`UpdateResults::write_to_checked`] report it without reading the file again
+    pub fn mismatch(p: impl AsRef<Path>) -> Option<Mismatch> {
+        Self::mismatch_sniffed(&p, &sniff(&p))
+    }
+
+    /// [`ImageType::mismatch`] with the first bytes of the file already read
+    pub fn mismatch_sniffed(p: impl AsRef<Path>, magic: &[u8]) -> Option<Mismatch> {
+        let extension = Self::from_path(&p);
+        let content = Self::from_sniffed(&p, magic);
+        (extension != content && extension != Self::Others)
+            .then_some(Mismatch { extension, content })
+    }
+}
+
+/// A file with the extension of one type which contains another
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mismatch {
+    /// The type the extension stands for
+    pub extension: ImageType,
+    /// The type detected from the content, which is the one the file is handled as
+    pub content: ImageType,
+}
+
+impl std::fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "extension of {:?} but content of {:?}",
+            self.extension, self.content
+        )
+    }
+}
+
+#[test]
+pub fn detect_magic() {
+    let detect = |data: &[u8]| ImageType::detect(data).unwrap();
+    let ftyp = |major: &[u8; 4], compatible: &[u8]| {
+        [
+            &((16 + compatible.len()) as u32).to_be_bytes()[..],
+            b"ftyp",
+            major,
+            &[0; 4],
+            compatible,
+        ]
+        .concat()
+    };
+    assert_eq!(detect(b"\xff\xd8\xff\xe0\0\x10JFIF"), Some(ImageType::Jpg));
+    assert_eq!(
+        detect(b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR"),
+        Some(ImageType::Png)
+    );
+    assert_eq!(detect(b"GIF89a\x01\0\x01\0"), Some(ImageType::Gif));
+    assert_eq!(detect(b"RIFF\x24\0\0\0WEBPVP8 "), Some(ImageType::WebP));
+    assert_eq!(detect(b"8BPS\0\x01\0\0"), Some(ImageType::Psd));
+    assert_eq!(detect(b"\xff\x0a\xfa\x1f"), Some(ImageType::Jxl));
+    assert_eq!(detect(b"II*\0\x08\0\0\0\x0e\0"), Some(ImageType::Tiff));
+    assert_eq!(detect(b"II*\0\x10\0\0\0CR\x02\0"), Some(ImageType::Raw));
+    assert_eq!(detect(b"IIRO\x08\0\0\0"), Some(ImageType::Raw));
+    assert_eq!(detect(&ftyp(b"heic", b"mif1heic")), Some(ImageType::Heif));
+    assert_eq!(
+        detect(&ftyp(b"mif1", b"mif1avifmiaf")),
+        Some(ImageType::Avif)
+    );
+    assert_eq!(detect(&ftyp(b"mif1", b"mif1heic")), Some(ImageType::Heif));
+    assert_eq!(detect(&ftyp(b"qt  ", b"qt  ")), Some(ImageType::Mp4));
+    assert_eq!(detect(&ftyp(b"crx ", b"crx isom")), Some(ImageType::Raw));
+    assert_eq!(detect(&ftyp(b"abcd", b"isom")), Some(ImageType::Mp4));
+    assert_eq!(detect(DEFAULT_XML.as_bytes()), Some(ImageType::Xmp));
+    assert_eq!(detect(b"%!PS-Adobe-3.0"), None);
+    assert_eq!(detect(b""), None);
+}
+
+#[cfg(feature = "heif")]
+#[test]
+pub fn detect_mislabeled() {
+    let dir = std::env::temp_dir().join("xmp_detect_mislabeled");
+    std::fs::create_dir_all(&dir).unwrap();
+    // An iPhone export renamed to jpg
+    let path = dir.join("IMG_0001.jpg");
+    std::fs::write(&path, isobmff::sample_isobmff(b"heic", b"hvc1", b"hvcC")).unwrap();
+    assert_eq!(ImageType::from_path(&path), ImageType::Jpg);
+    assert_eq!(ImageType::from_magic(&path), ImageType::Heif);
+    assert_eq!(
+        ImageType::mismatch(&path),
+        Some(Mismatch {
+            extension: ImageType::Jpg,
+            content: ImageType::Heif
+        })
+    );
+    // Tiff based raws aren't mislabeled tiffs
+    assert_eq!(ImageType::mismatch_sniffed("a.nef", b"MM\0*"), None);
+    assert_eq!(ImageType::mismatch_sniffed("a.eps", b"%!PS"), None);
+
+    let u = UpdateResults {
+        stars: Some(3),
+        ..Default::default()
+    };
+    let mismatch = u.write_to_checked(&path, Default::default()).unwrap();
+    assert_eq!(mismatch, ImageType::mismatch(&path));
+    let (results, mismatch) = OptionalResults::load_checked(&path).unwrap();
+    assert_eq!(results.stars, Some(3));
+    assert_eq!(mismatch.map(|m| m.content), Some(ImageType::Heif));
+    let renamed = dir.join("IMG_0001.heic");
+    std::fs::copy(&path, &renamed).unwrap();
+    assert_eq!(OptionalResults::load_checked(&renamed).unwrap().1, None);
+}
@@ -0,0 +1,628 @@
+//! Generic xmp data model covering every property of a packet, not just the ones
+//! [`UpdateResults`] knows about
+use crate::*;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+/// Namespace of the `xml:` prefix, which is bound without being declared
+pub const XML_NS: &str = "http://www.w3.org/XML/1998/namespace";
+
+/// Prefix to namespace bindings in scope of an element, `None` being the default namespace
+pub(crate) type Scope = BTreeMap<Option<String>, String>;
+
+/// A property, struct field or qualifier name
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct QName {
+    pub namespace: String,
+    pub name: String,
+}
+
+impl QName {
+    pub fn new(namespace: impl Into<String>, name: impl Into<String>) -> Self {
+        Self {
+            namespace: namespace.into(),
+            name: name.into(),
+        }
+    }
+
+    pub fn is(&self, namespace: &str, name: &str) -> bool {
+        self.namespace == namespace && self.name == name
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArrayKind {
+    /// Unordered, `rdf:Bag`
+    Bag,
+    /// Ordered, `rdf:Seq`
+    Seq,
+    /// Alternatives, `rdf:Alt`, usually the same text in several languages
+    Alt,
+}
+
+impl ArrayKind {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Bag => "Bag",
+            Self::Seq => "Seq",
+            Self::Alt => "Alt",
+        }
+    }
+
+    fn from_element(element: &Element) -> Option<Self> {
+        [Self::Bag, Self::Seq, Self::Alt]
+            .into_iter()
+            .find(|kind| element.is(kind.name(), RDF))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum XmpValue {
+    Simple(String),
+    Struct(Vec<(QName, XmpNode)>),
+    Array(ArrayKind, Vec<XmpNode>),
+}
+
+/// A value with its qualifiers, `xml:lang` being a qualifier in [`XML_NS`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XmpNode {
+    pub value: XmpValue,
+    pub qualifiers: Vec<(QName, XmpNode)>,
+}
+
+impl XmpNode {
+    pub fn new(value: XmpValue) -> Self {
+        Self {
+            value,
+            qualifiers: Vec::new(),
+        }
+    }
+
+    pub fn simple(value: impl Into<String>) -> Self {
+        Self::new(XmpValue::Simple(value.into()))
+    }
+
+    pub fn structure(fields: impl IntoIterator<Item = (QName, XmpNode)>) -> Self {
+        Self::new(XmpValue::Struct(fields.into_iter().collect()))
+    }
+
+    pub fn array(kind: ArrayKind, items: impl IntoIterator<Item = XmpNode>) -> Self {
+        Self::new(XmpValue::Array(kind, items.into_iter().collect()))
+    }
+
+    pub fn with_qualifier(
+        mut self,
+        namespace: impl Into<String>,
+        name: impl Into<String>,
+        qualifier: XmpNode,
+    ) -> Self {
+        self.qualifiers
+            .push((QName::new(namespace, name), qualifier));
+        self
+    }
+
+    pub fn with_lang(self, lang: impl Into<String>) -> Self {
+        self.with_qualifier(XML_NS, "lang", Self::simple(lang))
+    }
+
+    /// The text of a simple value
+    pub fn as_str(&self) -> Option<&str> {
+        match &self.value {
+            XmpValue::Simple(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// The items of an array, nothing for other values
+    pub fn items(&self) -> &[XmpNode] {
+        match &self.value {
+            XmpValue::Array(_, items) => items,
+            _ => &[],
+        }
+    }
+
+    pub fn field(&self, namespace: &str, name: &str) -> Option<&XmpNode> {
+        match &self.value {
+            XmpValue::Struct(fields) => find(fields, namespace, name),
+            _ => None,
+        }
+    }
+
+    pub fn qualifier(&self, namespace: &str, name: &str) -> Option<&XmpNode> {
+        find(&self.qualifiers, namespace, name)
+    }
+
+    pub fn lang(&self) -> Option<&str> {
+        self.qualifier(XML_NS, "lang").and_then(XmpNode::as_str)
+    }
+
+    /// A struct, or the qualified value given by its `rdf:value` field with the other fields as
+    /// its qualifiers
+    fn from_fields(mut fields: Vec<(QName, XmpNode)>) -> Self {
+        match fields.iter().position(|(name, _)| name.is(RDF, "value")) {
+            Some(index) => {
+                let (_, mut node) = fields.remove(index);
+                node.qualifiers.extend(fields);
+                node
+            }
+            None => Self::new(XmpValue::Struct(fields)),
+        }
+    }
+
+    fn namespaces<'a>(&'a self, out: &mut Vec<&'a str>) {
+        let fields = match &self.value {
+            XmpValue::Struct(fields) => fields.as_slice(),
+            _ => &[],
+        };
+        for (name, node) in fields.iter().chain(&self.qualifiers) {
+            out.push(&name.namespace);
+            node.namespaces(out);
+        }
+        for item in self.items() {
+            item.namespaces(out);
+        }
+    }
+}
+
+fn find<'a>(nodes: &'a [(QName, XmpNode)], namespace: &str, name: &str) -> Option<&'a XmpNode> {
+    nodes
+        .iter()
+        .find(|(n, _)| n.is(namespace, name))
+        .map(|(_, node)| node)
+}
+
+pub(crate) fn scope_of(parent: &Scope, element: &Element) -> Scope {
+    let mut scope = parent.clone();
+    for (prefix, namespace) in element.prefixes.declared_prefixes() {
+        scope.insert(prefix.clone(), namespace.clone());
+    }
+    scope
+}
+
+/// Resolves a prefixed attribute name, unprefixed attributes don't belong to any namespace
+pub(crate) fn attr_name(scope: &Scope, key: &str) -> Option<QName> {
+    let (prefix, name) = key.split_once(':')?;
+    let namespace = match prefix {
+        "xml" => XML_NS,
+        _ => scope.get(&Some(prefix.to_owned()))?,
+    };
+    Some(QName::new(namespace, name))
+}
+
+/// The key of the attribute `name` in `namespace`, whatever prefix the element uses for it
+pub(crate) fn attr_key<'a>(
+    element: &'a Element,
+    scope: &Scope,
+    namespace: &str,
+    name: &str,
+) -> Option<&'a str> {
+    element
+        .attrs()
+        .find(|(key, _)| attr_name(scope, key).is_some_and(|n| n.is(namespace, name)))
+        .map(|(key, _)| key)
+}
+
+pub(crate) fn attr<'a>(
+    element: &'a Element,
+    scope: &Scope,
+    namespace: &str,
+    name: &str,
+) -> Option<&'a str> {
+    attr_key(element, scope, namespace, name).and_then(|key| element.attr(key))
+}
+
+/// A prefix bound to `namespace`, the default namespace doesn't apply to attributes
+pub(crate) fn prefix_of<'a>(scope: &'a Scope, namespace: &str) -> Option<&'a str> {
+    scope
+        .iter()
+        .find(|(prefix, ns)| prefix.is_some() && *ns == namespace)
+        .and_then(|(prefix, _)| prefix.as_deref())
+}
+
+/// Properties given by the attributes and child elements of a description or struct
+fn parse_fields(element: &Element, parent: &Scope) -> Result<Vec<(QName, XmpNode)>, XmpError> {
+    let scope = scope_of(parent, element);
+    let mut fields = Vec::new();
+    for (key, value) in element.attrs() {
+        match attr_name(&scope, key) {
+            Some(name) if name.namespace == XML_NS => {}
+            // rdf:about, rdf:parseType and the like describe the element itself
+            Some(name) if name.namespace == RDF && name.name != "value" => {}
+            Some(name) => fields.push((name, XmpNode::simple(value))),
+            None => {}
+        }
+    }
+    for child in element.children() {
+        let name = QName::new(child.ns(), child.name());
+        fields.push((name, parse_node(child, &scope)?));
+    }
+    Ok(fields)
+}
+
+/// Parses a property element, in any of the forms the xmp specification allows
+fn parse_node(element: &Element, parent: &Scope) -> Result<XmpNode, XmpError> {
+    let scope = scope_of(parent, element);
+    let children: Vec<&Element> = element.children().collect();
+    let mut node = if let Some(uri) = attr(element, &scope, RDF, "resource") {
+        XmpNode::simple(uri)
+    } else if attr(element, &scope, RDF, "parseType") == Some("Resource") {
+        XmpNode::from_fields(parse_fields(element, parent)?)
+    } else if let [child] = children[..] {
+        if let Some(kind) = ArrayKind::from_element(child) {
+            let scope = scope_of(&scope, child);
+            let items = child
+                .children()
+                .filter(|li| li.is("li", RDF))
+                .map(|li| parse_node(li, &scope))
+                .collect::<Result<Vec<_>, _>>()?;
+            XmpNode::new(XmpValue::Array(kind, items))
+        } else if child.is("Description", RDF) {
+            XmpNode::from_fields(parse_fields(child, &scope)?)
+        } else {
+            return Err(XmpErrorKind::InvalidRdf.into());
+        }
+    } else if !children.is_empty() {
+        return Err(XmpErrorKind::InvalidRdf.into());
+    } else {
+        // Fields given as attributes of an otherwise empty element
+        let fields = parse_fields(element, parent)?;
+        if fields.is_empty() {
+            XmpNode::simple(element.text())
+        } else {
+            XmpNode::from_fields(fields)
+        }
+    };
+    if let Some(lang) = attr(element, &scope, XML_NS, "lang") {
+        node.qualifiers
+            .insert(0, (QName::new(XML_NS, "lang"), XmpNode::simple(lang)));
+    }
+    Ok(node)
+}
+
+fn collect_prefixes(element: &Element, prefixes: &mut BTreeMap<String, String>) {
+    for (prefix, namespace) in element.prefixes.declared_prefixes() {
+        if let Some(prefix) = prefix {
+            prefixes
+                .entry(namespace.clone())
+                .or_insert_with(|| prefix.clone());
+        }
+    }
+    for child in element.children() {
+        collect_prefixes(child, prefixes);
+    }
+}
+
+fn fill_value(element: &mut Element, value: &XmpValue) {
+    match value {
+        XmpValue::Simple(text) => {
+            if !text.is_empty() {
+                element.append_text_node(text.as_str());
+            }
+        }
+        XmpValue::Struct(fields) => {
+            element.set_attr("rdf:parseType", "Resource");
+            for (name, node) in fields {
+                element.append_child(node_element(name, node));
+            }
+        }
+        XmpValue::Array(kind, items) => {
+            let mut array = Element::bare(kind.name(), RDF);
+            for item in items {
+                array.append_child(node_element(&QName::new(RDF, "li"), item));
+            }
+            element.append_child(array);
+        }
+    }
+}
+
+fn node_element(name: &QName, node: &XmpNode) -> Element {
+    let mut element = Element::bare(name.name.as_str(), name.namespace.as_str());
+    let mut qualified = false;
+    for (qualifier, value) in &node.qualifiers {
+        match value.as_str() {
+            Some(lang) if qualifier.is(XML_NS, "lang") => element.set_attr("xml:lang", lang),
+            _ => qualified = true,
+        }
+    }
+    if !qualified {
+        fill_value(&mut element, &node.value);
+        return element;
+    }
+    element.set_attr("rdf:parseType", "Resource");
+    let mut value = Element::bare("value", RDF);
+    fill_value(&mut value, &node.value);
+    element.append_child(value);
+    for (qualifier, value) in &node.qualifiers {
+        if !qualifier.is(XML_NS, "lang") {
+            element.append_child(node_element(qualifier, value));
+        }
+    }
+    element
+}
+
+/// The properties of a packet, keyed by namespace uri and name
+///
+/// Properties from all `rdf:Description` elements are read into one set. Prefixes don't take
+/// part in lookups, the ones declared in the parsed packet are only reused when serializing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct XmpMeta {
+    about: String,
+    properties: Vec<(QName, XmpNode)>,
+    prefixes: BTreeMap<String, String>,
+}
+
+impl XmpMeta {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_reader<R: Read + Seek>(reader: R) -> Result<Self, XmpError> {
+        Self::from_element(&try_load_element(reader)?)
+    }
+
+    /// Reads the properties of an `x:xmpmeta` or `rdf:RDF` element
+    pub fn from_element(element: &Element) -> Result<Self, XmpError> {
+        let scope = scope_of(&Scope::new(), element);
+        let (rdf, scope) = if element.is("RDF", RDF) {
+            (element, scope)
+        } else {
+            let rdf = element
+                .get_child("RDF", RDF)
+                .otor(|| XmpErrorKind::ChildNotFound)?;
+            (rdf, scope_of(&scope, rdf))
+        };
+
+        let mut meta = Self::new();
+        collect_prefixes(element, &mut meta.prefixes);
+        for description in rdf.children().filter(|d| d.is("Description", RDF)) {
+            let description_scope = scope_of(&scope, description);
+            if let Some(about) = attr(description, &description_scope, RDF, "about") {
+                meta.about = about.to_owned();
+            }
+            // Like `try_find_item`, the first description holding a property wins
+            for (name, node) in parse_fields(description, &scope)? {
+                if meta.property(&name.namespace, &name.name).is_none() {
+                    meta.properties.push((name, node));
+                }
+            }
+        }
+        Ok(meta)
+    }
+
+    /// The `rdf:about` of the descriptions, usually empty
+    pub fn about(&self) -> &str {
+        &self.about
+    }
+
+    pub fn set_about(&mut self, about: impl Into<String>) {
+        self.about = about.into();
+    }
+
+    pub fn properties(&self) -> impl Iterator<Item = (&QName, &XmpNode)> {
+        self.properties.iter().map(|(name, node)| (name, node))
+    }
+
+    pub fn property(&self, namespace: &str, name: &str) -> Option<&XmpNode> {
+        find(&self.properties, namespace, name)
+    }
+
+    pub fn property_mut(&mut self, namespace: &str, name: &str) -> Option<&mut XmpNode> {
+        self.properties
+            .iter_mut()
+            .find(|(n, _)| n.is(namespace, name))
+            .map(|(_, node)| node)
+    }
+
+    /// Replaces the property, keeping its position, or adds it after the others
+    pub fn set_property(
+        &mut self,
+        namespace: impl Into<String>,
+        name: impl Into<String>,
+        node: XmpNode,
+    ) {
+        let name = QName::new(namespace, name);
+        match self.properties.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, existing)) => *existing = node,
+            None => self.properties.push((name, node)),
+        }
+    }
+
+    pub fn remove_property(&mut self, namespace: &str, name: &str) -> Option<XmpNode> {
+        let index = self
+            .properties
+            .iter()
+            .position(|(n, _)| n.is(namespace, name))?;
+        Some(self.properties.remove(index).1)
+    }
+
+    /// Picks a prefix for every namespace used, preferring the ones of the parsed packet
+    fn assign_prefixes(&self) -> BTreeMap<&str, String> {
+        let mut namespaces = Vec::new();
+        for (name, node) in &self.properties {
+            namespaces.push(name.namespace.as_str());
+            node.namespaces(&mut namespaces);
+        }
+
+        let mut prefixes = BTreeMap::new();
+        let mut taken: HashSet<String> = ["x", "rdf", "xml"].map(String::from).into();
+        for namespace in namespaces {
+            if namespace == RDF || namespace == XML_NS || prefixes.contains_key(namespace) {
+                continue;
+            }
+            let is_taken = |p: &str| taken.contains(p);
+            // The prefix the packet was read with wins over the registered one
+            let prefix = match self.prefixes.get(namespace) {
+                Some(preferred) => namespace::unique_prefix(preferred, is_taken),
+                None => namespace::assign(namespace, "ns", is_taken),
+            };
+            taken.insert(prefix.clone());
+            prefixes.insert(namespace, prefix);
+        }
+        prefixes
+    }
+
+    /// Builds the `x:xmpmeta` element with all properties in a single `rdf:Description`
+    pub fn to_element(&self) -> Element {
+        let mut description = Element::builder("Description", RDF)
+            .attr("rdf:about", self.about.as_str())
+            .build();
+        for (namespace, prefix) in self.assign_prefixes() {
+            description.prefixes.insert(Some(prefix), namespace);
+        }
+        for (name, node) in &self.properties {
+            description.append_child(node_element(name, node));
+        }
+
+        let mut rdf = Element::bare("RDF", RDF);
+        rdf.prefixes.insert(Some(String::from("rdf")), RDF);
+        rdf.append_child(description);
+        let mut xmpmeta = Element::bare("xmpmeta", X);
+        xmpmeta.prefixes.insert(Some(String::from("x")), X);
+        xmpmeta.append_child(rdf);
+        xmpmeta
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), XmpError> {
+        self.to_element().write_to(writer)?;
+        Ok(())
+    }
+
+    pub fn to_vec(&self) -> Result<Vec<u8>, XmpError> {
+        let mut xml = Vec::new();
+        self.write_to(&mut xml)?;
+        Ok(xml)
+    }
+}
+
+impl FromStr for XmpMeta {
+    type Err = XmpError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_reader(std::io::Cursor::new(s.as_bytes()))
+    }
+}
+
+#[cfg(test)]
+const SAMPLE_META: &str = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#"
+   xmlns:xap="http://ns.adobe.com/xap/1.0/">
+  <rdf:Description rdf:about="" xmlns:exif="http://ns.adobe.com/exif/1.0/" xap:Rating="3">
+   <xap:Label>Red &amp; Blue</xap:Label>
+   <exif:Flash exif:Fired="False" exif:Mode="2"/>
+  </rdf:Description>
+  <rdf:Description rdf:about="" xmlns:dc="http://purl.org/dc/elements/1.1/"
+    xmlns:Iptc4xmpCore="http://iptc.org/std/Iptc4xmpCore/1.0/xmlns/">
+   <dc:title>
+    <rdf:Alt>
+     <rdf:li xml:lang="x-default">Harbour</rdf:li>
+     <rdf:li xml:lang="de">Hafen</rdf:li>
+    </rdf:Alt>
+   </dc:title>
+   <dc:creator><rdf:Seq><rdf:li>Ann</rdf:li><rdf:li>Bob</rdf:li></rdf:Seq></dc:creator>
+   <dc:source rdf:resource="https://example.com/harbour"/>
+   <Iptc4xmpCore:CreatorContactInfo rdf:parseType="Resource">
+    <Iptc4xmpCore:CiAdrCity>Hamburg</Iptc4xmpCore:CiAdrCity>
+   </Iptc4xmpCore:CreatorContactInfo>
+   <dc:rights>
+    <rdf:Description>
+     <rdf:value>CC BY 4.0</rdf:value>
+     <xap:Nickname>attribution</xap:Nickname>
+    </rdf:Description>
+   </dc:rights>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>"#;
+
+#[test]
+pub fn meta_parse() {
+    let meta: XmpMeta = SAMPLE_META.parse().unwrap();
+    assert_eq!(meta.property(XMP, "Rating").unwrap().as_str(), Some("3"));
+    assert_eq!(
+        meta.property(XMP, "Label").unwrap().as_str(),
+        Some("Red & Blue")
+    );
+
+    let flash = meta.property(EXIF, "Flash").unwrap();
+    assert_eq!(flash.field(EXIF, "Fired").unwrap().as_str(), Some("False"));
+    assert_eq!(flash.field(EXIF, "Mode").unwrap().as_str(), Some("2"));
+
+    let title = meta.property(DC, "title").unwrap();
+    assert!(matches!(title.value, XmpValue::Array(ArrayKind::Alt, _)));
+    let langs: Vec<_> = title.items().iter().map(|i| i.lang().unwrap()).collect();
+    assert_eq!(langs, ["x-default", "de"]);
+    assert_eq!(title.items()[1].as_str(), Some("Hafen"));
+
+    let creators: Vec<_> = meta
+        .property(DC, "creator")
+        .unwrap()
+        .items()
+        .iter()
+        .map(|i| i.as_str().unwrap())
+        .collect();
+    assert_eq!(creators, ["Ann", "Bob"]);
+    assert_eq!(
+        meta.property(DC, "source").unwrap().as_str(),
+        Some("https://example.com/harbour")
+    );
+
+    let core = "http://iptc.org/std/Iptc4xmpCore/1.0/xmlns/";
+    let contact = meta.property(core, "CreatorContactInfo").unwrap();
+    assert_eq!(
+        contact.field(core, "CiAdrCity").unwrap().as_str(),
+        Some("Hamburg")
+    );
+
+    let rights = meta.property(DC, "rights").unwrap();
+    assert_eq!(rights.as_str(), Some("CC BY 4.0"));
+    assert_eq!(
+        rights.qualifier(XMP, "Nickname").unwrap().as_str(),
+        Some("attribution")
+    );
+}
+
+#[test]
+pub fn meta_round_trip() {
+    let meta: XmpMeta = SAMPLE_META.parse().unwrap();
+    let xml = String::from_utf8(meta.to_vec().unwrap()).unwrap();
+    // The prefixes of the packet are kept
+    assert!(xml.contains("xmlns:xap=\"http://ns.adobe.com/xap/1.0/\""));
+    assert_eq!(xml.matches("<rdf:Description").count(), 1);
+    let reparsed: XmpMeta = xml.parse().unwrap();
+    assert_eq!(reparsed.properties, meta.properties);
+
+    let mut meta = XmpMeta::new();
+    meta.set_property(
+        "http://example.com/a/",
+        "Keywords",
+        XmpNode::array(ArrayKind::Bag, [XmpNode::simple("<tag>")]),
+    );
+    // Clashes with the prefix picked for the first namespace
+    meta.set_property(
+        "http://example.com/b/",
+        "Note",
+        XmpNode::simple("text").with_lang("en"),
+    );
+    let reparsed: XmpMeta = String::from_utf8(meta.to_vec().unwrap())
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert_eq!(reparsed.properties, meta.properties);
+
+    let default: XmpMeta = DEFAULT_XML.parse().unwrap();
+    assert!(default.property(DC, "subject").unwrap().items().is_empty());
+}
+
+#[test]
+pub fn meta_duplicate_property() {
+    let xml = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description xmlns:xmp="http://ns.adobe.com/xap/1.0/" xmp:Rating="4"/>
+  <rdf:Description xmlns:xmp="http://ns.adobe.com/xap/1.0/" xmp:Rating="1" xmp:Label="Red"/>
+ </rdf:RDF>
+</x:xmpmeta>"#;
+    let meta: XmpMeta = xml.parse().unwrap();
+    assert_eq!(meta.property(XMP, "Rating").unwrap().as_str(), Some("4"));
+    assert_eq!(meta.property(XMP, "Label").unwrap().as_str(), Some("Red"));
+    let element: Element = xml.parse().unwrap();
+    assert_eq!(try_find_item(&element, XMP_RATING).unwrap(), "4");
+}
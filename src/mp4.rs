@@ -0,0 +1,376 @@
+use crate::isobmff::{boxes, encode_box, read_boxes, BoxHeader, IsoBox, XMP_UUID};
+use crate::*;
+use std::io::Cursor;
+
+/// Box holding the xmp packet in the user data of QuickTime movies
+const XMP_BOX: [u8; 4] = *b"XMP_";
+/// Boxes on the path from `moov` to the chunk offset tables
+const SAMPLE_TABLE_PATH: [[u8; 4]; 4] = [*b"trak", *b"mdia", *b"minf", *b"stbl"];
+
+/// Splits the body of `udta`, which QuickTime may end with a 32 bit 0 terminator
+fn udta_boxes(body: &[u8]) -> Result<(Vec<IsoBox<'_>>, &[u8]), XmpError> {
+    match boxes(body) {
+        Ok(children) => Ok((children, &[])),
+        Err(_) if body.ends_with(&[0; 4]) => {
+            let (children, terminator) = body.split_at(body.len() - 4);
+            Ok((boxes(children)?, terminator))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns the packet in `moov/udta/XMP_`
+fn udta_xml(moov: &[u8]) -> Result<Option<Vec<u8>>, XmpError> {
+    let moov = boxes(moov)?;
+    let moov = moov.first().otor(|| XmpErrorKind::InvalidIsobmff)?;
+    for udta in boxes(moov.body())?.iter().filter(|b| &b.kind == b"udta") {
+        let (children, _) = udta_boxes(udta.body())?;
+        if let Some(xmp) = children.iter().find(|b| b.kind == XMP_BOX) {
+            return Ok(Some(xmp.body().to_vec()));
+        }
+    }
+    Ok(None)
+}
+
+/// Returns `moov` with the packet in `udta/XMP_` added or replaced
+fn moov_with_xml(moov: &[u8], xml: &[u8]) -> Result<Vec<u8>, XmpError> {
+    let moov = boxes(moov)?;
+    let moov = moov.first().otor(|| XmpErrorKind::InvalidIsobmff)?;
+    let mut body = Vec::with_capacity(moov.data.len() + xml.len() + 16);
+    let mut written = false;
+    for child in boxes(moov.body())? {
+        if &child.kind != b"udta" || written {
+            body.extend_from_slice(child.data);
+            continue;
+        }
+        let (children, terminator) = udta_boxes(child.body())?;
+        let mut udta = Vec::with_capacity(child.data.len() + xml.len());
+        for grandchild in children.iter().filter(|b| b.kind != XMP_BOX) {
+            udta.extend_from_slice(grandchild.data);
+        }
+        udta.extend(encode_box(&XMP_BOX, xml));
+        udta.extend_from_slice(terminator);
+        body.extend(encode_box(b"udta", &udta));
+        written = true;
+    }
+    if !written {
+        body.extend(encode_box(b"udta", &encode_box(&XMP_BOX, xml)));
+    }
+    Ok(encode_box(b"moov", &body))
+}
+
+/// Collects the offsets of the `stco` and `co64` bodies in `data`
+fn chunk_offset_tables(
+    data: &[u8],
+    base: usize,
+    out: &mut Vec<(usize, [u8; 4])>,
+) -> Result<(), XmpError> {
+    for child in boxes(data)? {
+        let body = base + child.offset + child.header;
+        if SAMPLE_TABLE_PATH.contains(&child.kind) {
+            chunk_offset_tables(child.body(), body, out)?;
+        } else if &child.kind == b"stco" || &child.kind == b"co64" {
+            out.push((body, child.kind));
+        }
+    }
+    Ok(())
+}
+
+/// Rewrites the chunk offsets of every track in `moov` through `map`
+fn fix_chunk_offsets(
+    moov: &mut [u8],
+    map: impl Fn(u64) -> Result<u64, XmpError>,
+) -> Result<(), XmpError> {
+    let header = boxes(moov)?
+        .first()
+        .otor(|| XmpErrorKind::InvalidIsobmff)?
+        .header;
+    let mut tables = Vec::new();
+    chunk_offset_tables(&moov[header..], header, &mut tables)?;
+    for (offset, kind) in tables {
+        let size = if &kind == b"co64" { 8 } else { 4 };
+        // Version and flags followed by the entry count
+        let count = moov
+            .get(offset + 4..offset + 8)
+            .otor(|| XmpErrorKind::InvalidIsobmff)?;
+        let count = u32::from_be_bytes([count[0], count[1], count[2], count[3]]) as usize;
+        let entries = moov
+            .get_mut(offset + 8..offset + 8 + count * size)
+            .otor(|| XmpErrorKind::InvalidIsobmff)?;
+        for entry in entries.chunks_exact_mut(size) {
+            if size == 8 {
+                let mut old = [0; 8];
+                old.copy_from_slice(entry);
+                entry.copy_from_slice(&map(u64::from_be_bytes(old))?.to_be_bytes());
+            } else {
+                let old = u32::from_be_bytes([entry[0], entry[1], entry[2], entry[3]]);
+                let new =
+                    u32::try_from(map(old.into())?).map_err(|_| XmpErrorKind::InvalidIsobmff)?;
+                entry.copy_from_slice(&new.to_be_bytes());
+            }
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn __mp4_xml<R: Read + Seek>(reader: &mut R) -> Result<Vec<u8>, XmpError> {
+    let top = read_boxes(reader)?;
+    if let Some(uuid) = top.iter().find(|b| b.usertype == Some(XMP_UUID)) {
+        let data = uuid.read(reader)?;
+        return Ok(data[uuid.header as usize..].to_vec());
+    }
+    if let Some(moov) = top.iter().find(|b| &b.kind == b"moov") {
+        if let Some(xml) = udta_xml(&moov.read(reader)?)? {
+            return Ok(xml);
+        }
+    }
+    Err(XmpErrorKind::XMPMissing.into())
+}
+
+/// A top level box of the rewritten file
+enum Part {
+    Copy(BoxHeader),
+    Data(Vec<u8>),
+}
+
+/// Writes the file with the packet in the top level `uuid` box and, for QuickTime movies or
+/// files which already have one, in `moov/udta/XMP_`
+///
+/// Only `moov` and the `uuid` box are held in memory, every other box is streamed through. The
+/// chunk offsets are updated when the media data moves.
+pub(crate) fn __mp4_write_xml<R: Read + Seek, W: Write>(
+    reader: &mut R,
+    xml: &[u8],
+    writer: &mut W,
+) -> Result<(), XmpError> {
+    let top = read_boxes(reader)?;
+    let quicktime = match top.first() {
+        Some(ftyp) if &ftyp.kind == b"ftyp" => ftyp.read(reader)?.get(8..12) == Some(b"qt  "),
+        _ => false,
+    };
+    let uuid = top.iter().position(|b| b.usertype == Some(XMP_UUID));
+    let moov_index = top.iter().position(|b| &b.kind == b"moov");
+    let mut moov = moov_index.map(|i| top[i].read(reader)).transpose()?;
+
+    let has_udta_xml = match &moov {
+        Some(moov) => udta_xml(moov)?.is_some(),
+        None => false,
+    };
+    let write_udta = has_udta_xml || (quicktime && uuid.is_none());
+    if write_udta {
+        let old = moov.as_deref().otor(|| XmpErrorKind::InvalidIsobmff)?;
+        moov = Some(moov_with_xml(old, xml)?);
+    }
+    let uuid_box = (!write_udta || uuid.is_some())
+        .then(|| encode_box(b"uuid", &[&XMP_UUID[..], xml].concat()));
+
+    let mut parts = Vec::with_capacity(top.len() + 1);
+    for (i, header) in top.iter().enumerate() {
+        if Some(i) == uuid {
+            parts.extend(uuid_box.clone().map(Part::Data));
+        } else if Some(i) == moov_index {
+            parts.push(Part::Data(
+                moov.take().otor(|| XmpErrorKind::InvalidIsobmff)?,
+            ));
+        } else {
+            parts.push(Part::Copy(*header));
+        }
+    }
+    if let (None, Some(uuid_box)) = (uuid, uuid_box) {
+        // A box extending to the end of the file has to stay last
+        let index = match parts.last() {
+            Some(Part::Copy(last)) if last.to_end => parts.len() - 1,
+            _ => parts.len(),
+        };
+        parts.insert(index, Part::Data(uuid_box));
+    }
+
+    // Where each copied box starts in the new file
+    let mut moved = Vec::new();
+    let mut offset = 0;
+    for part in &parts {
+        match part {
+            Part::Copy(header) => {
+                moved.push((header.offset, header.offset + header.size, offset));
+                offset += header.size;
+            }
+            Part::Data(data) => offset += data.len() as u64,
+        }
+    }
+    let map = |old: u64| {
+        moved
+            .iter()
+            .find(|(start, end, _)| (*start..*end).contains(&old))
+            .map(|(start, _, new)| old - start + new)
+            .otor(|| XmpErrorKind::InvalidIsobmff)
+            .map_err(XmpError::from)
+    };
+
+    for part in &mut parts {
+        match part {
+            Part::Data(data) if &data[4..8] == b"moov" => fix_chunk_offsets(data, map)?,
+            _ => (),
+        }
+    }
+
+    for part in parts {
+        match part {
+            Part::Copy(header) => {
+                reader.seek(SeekFrom::Start(header.offset))?;
+                let copied = std::io::copy(&mut reader.take(header.size), writer)?;
+                if copied != header.size {
+                    return Err(XmpErrorKind::InvalidIsobmff.into());
+                }
+            }
+            Part::Data(data) => writer.write_all(&data)?,
+        }
+    }
+    Ok(())
+}
+
+impl UpdateResults {
+    pub fn update_mp4(
+        &self,
+        path: impl AsRef<Path>,
+        options: UpdateOptions,
+    ) -> Result<(), XmpError> {
+        let mut reader = BufReader::new(std::fs::File::open(&path)?);
+        replace_file(&path, |writer| self.apply_mp4(&mut reader, writer, options))
+    }
+
+    pub fn apply_mp4<R: Read + Seek, W: Write>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+        options: UpdateOptions,
+    ) -> Result<(), XmpError> {
+        let xml = __mp4_xml(reader).unwrap_or_else(|_e| DEFAULT_XML.as_bytes().to_vec());
+        let xml = self.update_xml(Cursor::new(xml), options)?;
+        __mp4_write_xml(reader, &xml, writer)
+    }
+}
+
+impl OptionalResults {
+    pub fn load_mp4(path: impl AsRef<Path>) -> Result<Self, XmpError> {
+        Self::read_mp4(&mut BufReader::new(std::fs::File::open(path)?))
+    }
+
+    pub fn read_mp4<R: Read + Seek>(reader: &mut R) -> Result<Self, XmpError> {
+        Self::from_reader(Cursor::new(__mp4_xml(reader)?))
+    }
+}
+
+/// A movie with one track whose two chunks are found through `stco`
+#[cfg(test)]
+fn sample_mp4(brand: &[u8; 4], moov_first: bool) -> Vec<u8> {
+    let ftyp = encode_box(b"ftyp", &[&brand[..], &[0, 0, 2, 0], brand].concat());
+    let mdat_body = b"first chunksecond chunk";
+    let mdat = encode_box(b"mdat", mdat_body);
+    let moov = |mdat_offset: u32| {
+        let stco = [
+            &[0, 0, 0, 0][..],
+            &2u32.to_be_bytes(),
+            &(mdat_offset + 8).to_be_bytes(),
+            &(mdat_offset + 8 + 11).to_be_bytes(),
+        ]
+        .concat();
+        let stbl = encode_box(b"stbl", &encode_box(b"stco", &stco));
+        let minf = encode_box(b"minf", &stbl);
+        let mdia = encode_box(b"mdia", &minf);
+        let trak = encode_box(b"trak", &[encode_box(b"tkhd", &[0; 84]), mdia].concat());
+        encode_box(b"moov", &[encode_box(b"mvhd", &[0; 100]), trak].concat())
+    };
+    if moov_first {
+        let offset = (ftyp.len() + moov(0).len()) as u32;
+        [ftyp, moov(offset), mdat].concat()
+    } else {
+        let offset = ftyp.len() as u32;
+        [ftyp, mdat, moov(offset)].concat()
+    }
+}
+
+/// Returns the data of every chunk found through the chunk offset table
+#[cfg(test)]
+fn chunks(data: &[u8]) -> Vec<Vec<u8>> {
+    let top = read_boxes(&mut Cursor::new(data)).unwrap();
+    let moov = top.iter().find(|b| &b.kind == b"moov").unwrap();
+    let moov = &data[moov.offset as usize..(moov.offset + moov.size) as usize];
+    let mut tables = Vec::new();
+    chunk_offset_tables(&moov[8..], 8, &mut tables).unwrap();
+    let (offset, _) = tables[0];
+    [0, 1]
+        .iter()
+        .map(|i| {
+            let entry = &moov[offset + 8 + i * 4..offset + 12 + i * 4];
+            let start = u32::from_be_bytes([entry[0], entry[1], entry[2], entry[3]]) as usize;
+            data[start..start + if *i == 0 { 11 } else { 12 }].to_vec()
+        })
+        .collect()
+}
+
+#[test]
+pub fn mp4_write_xml() {
+    let expected = vec![b"first chunk".to_vec(), b"second chunk".to_vec()];
+    for (brand, moov_first) in [
+        (b"isom", true),
+        (b"isom", false),
+        (b"qt  ", true),
+        (b"qt  ", false),
+    ] {
+        let original = sample_mp4(brand, moov_first);
+        assert_eq!(chunks(&original), expected);
+
+        let mut data = Vec::new();
+        __mp4_write_xml(
+            &mut Cursor::new(&original),
+            DEFAULT_XML.as_bytes(),
+            &mut data,
+        )
+        .unwrap();
+        assert_eq!(chunks(&data), expected);
+        assert_eq!(
+            __mp4_xml(&mut Cursor::new(&data)).unwrap(),
+            DEFAULT_XML.as_bytes()
+        );
+        let top = read_boxes(&mut Cursor::new(&data)).unwrap();
+        let has_uuid = top.iter().any(|b| b.usertype == Some(XMP_UUID));
+        let moov = top.iter().find(|b| &b.kind == b"moov").unwrap();
+        let has_udta = udta_xml(&moov.read(&mut Cursor::new(&data)).unwrap())
+            .unwrap()
+            .is_some();
+        assert_eq!((has_uuid, has_udta), (brand != b"qt  ", brand == b"qt  "));
+
+        // Replacing the packet with a smaller one moves the media data back
+        let mut updated = Vec::new();
+        __mp4_write_xml(&mut Cursor::new(&data), b"<x:xmpmeta/>", &mut updated).unwrap();
+        assert_eq!(chunks(&updated), expected);
+        assert_eq!(
+            __mp4_xml(&mut Cursor::new(&updated)).unwrap(),
+            b"<x:xmpmeta/>"
+        );
+        assert_eq!(
+            updated.len(),
+            data.len() - DEFAULT_XML.len() + b"<x:xmpmeta/>".len()
+        );
+    }
+}
+
+#[test]
+pub fn mp4_update() {
+    let dir = std::env::temp_dir().join("xmp_mp4_update");
+    std::fs::create_dir_all(&dir).unwrap();
+    for (name, brand) in [("clip.mp4", b"isom"), ("clip.mov", b"qt  ")] {
+        let path = dir.join(name);
+        std::fs::write(&path, sample_mp4(brand, true)).unwrap();
+
+        let u = UpdateResults {
+            stars: Some(5),
+            colors: Some(String::from("Red")),
+            ..Default::default()
+        };
+        u.update(&path).unwrap();
+        let r = OptionalResults::load(&path).unwrap();
+        assert_eq!(r.stars, Some(5));
+        assert_eq!(r.colors.as_deref(), Some("Red"));
+    }
+}
@@ -0,0 +1,252 @@
+//! Namespaces known to the crate and the prefixes they are written with
+use crate::*;
+use std::sync::{OnceLock, PoisonError, RwLock};
+
+/// Standard namespaces with their preferred prefixes
+const STANDARD: [(&str, &str); 33] = [
+    (X, "x"),
+    (RDF, "rdf"),
+    (meta::XML_NS, "xml"),
+    (XMP, "xmp"),
+    (XMP_MM, "xmpMM"),
+    (XMP_RIGHTS, "xmpRights"),
+    (XMP_BJ, "xmpBJ"),
+    (XMP_TPG, "xmpTPg"),
+    (XMP_DM, "xmpDM"),
+    (XMP_G_IMG, "xmpGImg"),
+    (XMPNOTE, "xmpNote"),
+    (ST_EVT, "stEvt"),
+    (ST_REF, "stRef"),
+    (ST_DIM, "stDim"),
+    (ST_AREA, "stArea"),
+    (DC, "dc"),
+    (PDF, "pdf"),
+    (PHOTOSHOP, "photoshop"),
+    (TIFF, "tiff"),
+    (EXIF, "exif"),
+    (EXIF_EX, "exifEX"),
+    (AUX, "aux"),
+    (CRS, "crs"),
+    (CRD, "crd"),
+    (LR, "lr"),
+    (IPTC_CORE, "Iptc4xmpCore"),
+    (IPTC_EXT, "Iptc4xmpExt"),
+    (PLUS, "plus"),
+    (MWG_RS, "mwg-rs"),
+    (MWG_KW, "mwg-kw"),
+    (MWG_COLL, "mwg-coll"),
+    (XMP_IDQ, "xmpidq"),
+    (ST_JOB, "stJob"),
+];
+
+const fn str_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < a.len() {
+        if a[i] != b[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Preferred prefix of a standard namespace, usable in constants like the ones [`make_item!`]
+/// defines
+///
+/// Panics when `namespace` isn't one of the standard namespaces, which fails the build when
+/// evaluated in a constant.
+pub const fn standard_prefix(namespace: &str) -> &'static str {
+    let mut i = 0;
+    while i < STANDARD.len() {
+        if str_eq(STANDARD[i].0, namespace) {
+            return STANDARD[i].1;
+        }
+        i += 1;
+    }
+    panic!("not a standard namespace, give the prefix to make_item! explicitly")
+}
+
+/// Whether `prefix` is a valid xml name without a colon
+fn valid_prefix(prefix: &str) -> bool {
+    let mut chars = prefix.chars();
+    chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '.'))
+        && !prefix.eq_ignore_ascii_case("xmlns")
+}
+
+/// Namespace URIs with the prefixes they are preferably written with
+///
+/// Several namespaces may prefer the same prefix, the clash is resolved when a packet is written
+/// by appending a number to the prefix of the namespace bound last.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NamespaceRegistry {
+    namespaces: Vec<(String, String)>,
+}
+
+impl Default for NamespaceRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NamespaceRegistry {
+    /// A registry holding the standard Adobe, IPTC, EXIF, MWG and Lightroom namespaces
+    pub fn new() -> Self {
+        Self {
+            namespaces: STANDARD
+                .iter()
+                .map(|(namespace, prefix)| (namespace.to_string(), prefix.to_string()))
+                .collect(),
+        }
+    }
+
+    /// Registers `namespace` to be written with `prefix`, replacing the prefix it had before
+    pub fn register(
+        &mut self,
+        namespace: impl Into<String>,
+        prefix: impl Into<String>,
+    ) -> Result<(), XmpError> {
+        let (namespace, prefix) = (namespace.into(), prefix.into());
+        if namespace.is_empty() || !valid_prefix(&prefix) {
+            return Err(XmpErrorKind::InvalidNamespace.into());
+        }
+        // The prefixes bound by the xml specification can't be changed
+        if namespace == meta::XML_NS || prefix == "xml" {
+            return Err(XmpErrorKind::InvalidNamespace.into());
+        }
+        match self.namespaces.iter_mut().find(|(ns, _)| *ns == namespace) {
+            Some((_, existing)) => *existing = prefix,
+            None => self.namespaces.push((namespace, prefix)),
+        }
+        Ok(())
+    }
+
+    /// The prefix `namespace` is preferably written with
+    pub fn prefix(&self, namespace: &str) -> Option<&str> {
+        self.namespaces
+            .iter()
+            .find(|(ns, _)| ns == namespace)
+            .map(|(_, prefix)| prefix.as_str())
+    }
+
+    /// The namespace preferring `prefix`, the one registered first if several do
+    pub fn namespace(&self, prefix: &str) -> Option<&str> {
+        self.namespaces
+            .iter()
+            .find(|(_, p)| p == prefix)
+            .map(|(namespace, _)| namespace.as_str())
+    }
+
+    /// The prefix to bind `namespace` to, given whether a prefix is `taken` by another namespace
+    /// already. Namespaces which aren't registered are written as `ns`
+    pub fn assign(&self, namespace: &str, taken: impl Fn(&str) -> bool) -> String {
+        self.assign_or(namespace, "ns", taken)
+    }
+
+    /// Like [`NamespaceRegistry::assign`], writing unregistered namespaces as `fallback`
+    pub(crate) fn assign_or(
+        &self,
+        namespace: &str,
+        fallback: &str,
+        taken: impl Fn(&str) -> bool,
+    ) -> String {
+        unique_prefix(self.prefix(namespace).unwrap_or(fallback), taken)
+    }
+}
+
+/// `preferred`, or `preferred` with the lowest number appended which isn't `taken`
+pub(crate) fn unique_prefix(preferred: &str, taken: impl Fn(&str) -> bool) -> String {
+    let mut prefix = preferred.to_owned();
+    let mut n = 1;
+    while prefix == "xml" || prefix == "xmlns" || taken(&prefix) {
+        prefix = format!("{}{}", preferred, n);
+        n += 1;
+    }
+    prefix
+}
+
+fn global() -> &'static RwLock<NamespaceRegistry> {
+    static REGISTRY: OnceLock<RwLock<NamespaceRegistry>> = OnceLock::new();
+    REGISTRY.get_or_init(|| RwLock::new(NamespaceRegistry::new()))
+}
+
+/// Registers a namespace with the registry used when writing packets
+pub fn register(namespace: impl Into<String>, prefix: impl Into<String>) -> Result<(), XmpError> {
+    global()
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .register(namespace, prefix)
+}
+
+/// The prefix to bind `namespace` to according to the global registry, see
+/// [`NamespaceRegistry::assign`]
+pub(crate) fn assign(namespace: &str, fallback: &str, taken: impl Fn(&str) -> bool) -> String {
+    global()
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .assign_or(namespace, fallback, taken)
+}
+
+#[test]
+pub fn namespace_registry() {
+    let mut registry = NamespaceRegistry::new();
+    assert_eq!(registry.prefix(IPTC_CORE), Some("Iptc4xmpCore"));
+    assert_eq!(registry.prefix(ST_EVT), Some("stEvt"));
+    assert_eq!(registry.namespace("xmp"), Some(XMP));
+    assert_eq!(registry.prefix("http://example.com/ns/"), None);
+
+    registry.register("http://example.com/ns/", "ex").unwrap();
+    assert_eq!(registry.prefix("http://example.com/ns/"), Some("ex"));
+    // A custom namespace preferring a standard prefix
+    registry.register("http://example.com/dc/", "dc").unwrap();
+    assert_eq!(registry.namespace("dc"), Some(DC));
+    assert_eq!(
+        registry.assign("http://example.com/dc/", |p| p == "dc"),
+        "dc1"
+    );
+    assert_eq!(
+        registry.assign("http://example.com/other/", |_| false),
+        "ns"
+    );
+    assert!(registry.register("http://example.com/ns/", "1ex").is_err());
+    assert!(registry.register("http://example.com/ns/", "xml").is_err());
+    assert!(registry.register("", "ex").is_err());
+
+    assert_eq!(standard_prefix(MWG_RS), "mwg-rs");
+    make_item!(IPTC_CORE, "Location");
+    assert_eq!(IPTC_CORE_LOCATION.attr_name(), "Iptc4xmpCore:Location");
+    const EXAMPLE: &str = "http://example.com/ns/";
+    make_item!(EXAMPLE, "Flag", "ex");
+    assert_eq!(EXAMPLE_FLAG.attr_name(), "ex:Flag");
+}
+
+#[test]
+pub fn namespace_register() {
+    use std::io::Cursor;
+
+    const CUSTOM: &str = "http://example.com/xmp/namespace_register/";
+    const CLASHING: &str = "http://example.com/xmp/namespace_register/dc/";
+    register(CUSTOM, "nsReg").unwrap();
+    register(CLASHING, "dc").unwrap();
+
+    let mut meta = meta::XmpMeta::new();
+    meta.set_property(DC, "format", meta::XmpNode::simple("image/jpeg"));
+    meta.set_property(CUSTOM, "Flag", meta::XmpNode::simple("on"));
+    meta.set_property(CLASHING, "Flag", meta::XmpNode::simple("off"));
+    meta.set_property(IPTC_CORE, "Location", meta::XmpNode::simple("Harbour"));
+    let xml = String::from_utf8(meta.to_vec().unwrap()).unwrap();
+    assert!(xml.contains(&format!("xmlns:nsReg=\"{}\"", CUSTOM)));
+    assert!(xml.contains(&format!("xmlns:dc=\"{}\"", DC)));
+    assert!(xml.contains(&format!("xmlns:dc1=\"{}\"", CLASHING)));
+    assert!(xml.contains(&format!("xmlns:Iptc4xmpCore=\"{}\"", IPTC_CORE)));
+
+    let meta = meta::XmpMeta::from_reader(Cursor::new(xml)).unwrap();
+    assert_eq!(
+        meta.property(CLASHING, "Flag").and_then(|n| n.as_str()),
+        Some("off")
+    );
+}
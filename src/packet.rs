@@ -0,0 +1,436 @@
+//! Finds xmp packets in files without a dedicated backend by scanning for the `<?xpacket?>`
+//! wrappers as described in part 3 of the XMP spec
+
+use crate::*;
+use std::io::Cursor;
+
+const HEADER: &str = "<?xpacket begin=";
+const TRAILER: &str = "<?xpacket end=";
+/// Size of the reads while scanning
+const CHUNK: usize = 1 << 16;
+/// Packets larger than this are assumed to be a stray header without a trailer
+const MAX_PACKET: usize = 64 << 20;
+
+/// Character encoding of a packet, given by how its header is encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketEncoding {
+    Utf8,
+    Utf16BE,
+    Utf16LE,
+    Utf32BE,
+    Utf32LE,
+}
+
+const ENCODINGS: [PacketEncoding; 5] = [
+    PacketEncoding::Utf8,
+    PacketEncoding::Utf16BE,
+    PacketEncoding::Utf16LE,
+    PacketEncoding::Utf32BE,
+    PacketEncoding::Utf32LE,
+];
+
+impl PacketEncoding {
+    /// Length of a code unit in bytes
+    pub fn unit(self) -> usize {
+        match self {
+            Self::Utf8 => 1,
+            Self::Utf16BE | Self::Utf16LE => 2,
+            Self::Utf32BE | Self::Utf32LE => 4,
+        }
+    }
+
+    pub fn encode(self, text: &str) -> Vec<u8> {
+        match self {
+            Self::Utf8 => text.as_bytes().to_vec(),
+            Self::Utf16BE => text.encode_utf16().flat_map(u16::to_be_bytes).collect(),
+            Self::Utf16LE => text.encode_utf16().flat_map(u16::to_le_bytes).collect(),
+            Self::Utf32BE => text
+                .chars()
+                .flat_map(|c| u32::from(c).to_be_bytes())
+                .collect(),
+            Self::Utf32LE => text
+                .chars()
+                .flat_map(|c| u32::from(c).to_le_bytes())
+                .collect(),
+        }
+    }
+
+    pub fn decode(self, data: &[u8]) -> Result<String, XmpError> {
+        let invalid = || XmpErrorKind::InvalidPacket;
+        let units = data.chunks_exact(self.unit());
+        if !units.remainder().is_empty() {
+            return Err(invalid().into());
+        }
+        Ok(match self {
+            Self::Utf8 => String::from_utf8(data.to_vec())?,
+            Self::Utf16BE => String::from_utf16(
+                &units
+                    .map(|u| u16::from_be_bytes([u[0], u[1]]))
+                    .collect::<Vec<_>>(),
+            )
+            .map_err(|_| invalid())?,
+            Self::Utf16LE => String::from_utf16(
+                &units
+                    .map(|u| u16::from_le_bytes([u[0], u[1]]))
+                    .collect::<Vec<_>>(),
+            )
+            .map_err(|_| invalid())?,
+            Self::Utf32BE | Self::Utf32LE => units
+                .map(|u| {
+                    let u = [u[0], u[1], u[2], u[3]];
+                    let u = if self == Self::Utf32BE {
+                        u32::from_be_bytes(u)
+                    } else {
+                        u32::from_le_bytes(u)
+                    };
+                    char::from_u32(u).otor(invalid)
+                })
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+/// An xmp packet found in a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Packet {
+    /// Offset of the `<?xpacket begin=` header
+    pub offset: u64,
+    /// Length from the header up to the end of the trailer, including the padding
+    pub length: u64,
+    pub encoding: PacketEncoding,
+    /// Whether the trailer is `<?xpacket end="w"?>`, allowing the packet to be rewritten in place
+    pub writable: bool,
+}
+
+/// Scans `reader` for every xmp packet in the order they appear
+///
+/// A header without a trailer is skipped. Packets nested in other packets aren't reported, and
+/// neither are packets in compressed parts of the file.
+pub fn scan<R: Read>(mut reader: R) -> Result<Vec<Packet>, XmpError> {
+    let headers = ENCODINGS.map(|encoding| encoding.encode(HEADER));
+    let trailers = ENCODINGS.map(|encoding| encoding.encode(TRAILER));
+    let longest = headers.iter().map(Vec::len).max().unwrap_or_default();
+
+    let mut packets = Vec::new();
+    let mut buffer = Vec::new();
+    // File offset of the start of the buffer
+    let mut base = 0;
+    // Where the search continues and the packet being read, if its header was found
+    let mut pos = 0;
+    let mut current: Option<(usize, PacketEncoding)> = None;
+    let mut chunk = vec![0; CHUNK];
+    let mut eof = false;
+    loop {
+        match current {
+            None => {
+                let found = ENCODINGS
+                    .iter()
+                    .zip(&headers)
+                    .filter_map(|(encoding, header)| {
+                        Some((find(&buffer[pos..], header)? + pos, *encoding, header.len()))
+                    })
+                    .min_by_key(|(start, _, _)| *start);
+                if let Some((start, encoding, len)) = found {
+                    current = Some((start, encoding));
+                    pos = start + len;
+                    continue;
+                }
+                // Keep the bytes a header could straddle
+                let keep = buffer.len().saturating_sub(longest - 1);
+                buffer.drain(..keep);
+                base += keep as u64;
+                pos = 0;
+            }
+            Some((start, encoding)) => {
+                let index = ENCODINGS.iter().position(|e| *e == encoding).unwrap_or(0);
+                let trailer = &trailers[index];
+                if let Some(at) = find(&buffer[pos..], trailer).map(|at| at + pos) {
+                    // The quoted `r` or `w` and the closing `?>`
+                    let end = at + trailer.len() + 5 * encoding.unit();
+                    if let Some(rest) = buffer.get(at + trailer.len()..end) {
+                        let rest = encoding.decode(rest).unwrap_or_default();
+                        let writable = match rest.as_str() {
+                            "'w'?>" | "\"w\"?>" => Some(true),
+                            "'r'?>" | "\"r\"?>" => Some(false),
+                            _ => None,
+                        };
+                        match writable {
+                            Some(writable) => {
+                                packets.push(Packet {
+                                    offset: base + start as u64,
+                                    length: (end - start) as u64,
+                                    encoding,
+                                    writable,
+                                });
+                                current = None;
+                                pos = end;
+                            }
+                            None => pos = at + 1,
+                        }
+                        continue;
+                    }
+                    pos = at;
+                } else if buffer.len() - start > MAX_PACKET {
+                    current = None;
+                    pos = start + 1;
+                    continue;
+                } else {
+                    pos = buffer.len().saturating_sub(trailer.len() - 1).max(pos);
+                }
+            }
+        }
+        if eof {
+            return Ok(packets);
+        }
+        let read = reader.read(&mut chunk)?;
+        eof = read == 0;
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+impl Packet {
+    /// Reads the packet and returns the serialized metadata between the header and the trailer,
+    /// converted to UTF-8
+    pub fn read_xml<R: Read + Seek>(&self, reader: &mut R) -> Result<Vec<u8>, XmpError> {
+        let mut data = vec![0; self.length as usize];
+        reader.seek(SeekFrom::Start(self.offset))?;
+        reader.read_exact(&mut data)?;
+        let text = self.encoding.decode(&data)?;
+        let start = text.find("?>").otor(|| XmpErrorKind::InvalidPacket)? + 2;
+        let end = text.rfind(TRAILER).otor(|| XmpErrorKind::InvalidPacket)?;
+        let xml = text.get(start..end).otor(|| XmpErrorKind::InvalidPacket)?;
+        Ok(xml.trim().as_bytes().to_vec())
+    }
+
+    /// Returns a packet holding `xml` of exactly the same length and encoding, with the difference
+    /// taken up by the padding
+    pub fn encode(&self, xml: &[u8]) -> Result<Vec<u8>, XmpError> {
+        if !self.writable {
+            return Err(XmpErrorKind::ReadOnlyPacket.into());
+        }
+        let xml = std::str::from_utf8(xml).map_err(|_| XmpErrorKind::InvalidPacket)?;
+        let header = "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n";
+        let trailer = "<?xpacket end=\"w\"?>";
+        let mut out = self.encoding.encode(header);
+        out.extend(self.encoding.encode(xml));
+        out.extend(self.encoding.encode("\n"));
+        let trailer = self.encoding.encode(trailer);
+        let padding = (self.length as usize)
+            .checked_sub(out.len() + trailer.len())
+            .otor(|| XmpErrorKind::PacketDoesNotFit)?;
+        // Spaces with a newline every 100 characters as the spec recommends
+        let mut padding = padding / self.encoding.unit();
+        while padding > 0 {
+            let line = padding.min(100);
+            out.extend(self.encoding.encode(&" ".repeat(line - 1)));
+            out.extend(self.encoding.encode("\n"));
+            padding -= line;
+        }
+        out.extend(trailer);
+        Ok(out)
+    }
+
+    /// Overwrites the packet in place with one holding `xml`, leaving the rest of the file
+    /// untouched
+    pub fn rewrite<W: Write + Seek>(&self, writer: &mut W, xml: &[u8]) -> Result<(), XmpError> {
+        let data = self.encode(xml)?;
+        writer.seek(SeekFrom::Start(self.offset))?;
+        writer.write_all(&data)?;
+        Ok(())
+    }
+}
+
+/// The packet used for files without a dedicated backend, the last one since updates are often
+/// appended and earlier packets may belong to embedded images
+fn main_packet<R: Read + Seek>(reader: &mut R) -> Result<Packet, XmpError> {
+    reader.seek(SeekFrom::Start(0))?;
+    scan(&mut *reader)?
+        .pop()
+        .otor(|| XmpErrorKind::XMPMissing)
+        .map_err(Into::into)
+}
+
+impl UpdateResults {
+    /// Rewrites the packet found by scanning in place, which fails if the packet is read-only or
+    /// the update doesn't fit in its padding
+    pub fn update_packet(
+        &self,
+        path: impl AsRef<Path>,
+        options: UpdateOptions,
+    ) -> Result<(), XmpError> {
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)?;
+        let packet = main_packet(&mut BufReader::new(&mut file))?;
+        let xml = packet.read_xml(&mut BufReader::new(&mut file))?;
+        let xml = self.update_xml(Cursor::new(xml), options)?;
+        packet
+            .rewrite(&mut file, &xml)
+            .map_err(|e| e.with_name(path.as_ref()))?;
+        file.flush()?;
+
+        Ok(())
+    }
+
+    /// Copies the file to `writer` with the packet found by scanning replaced, under the same
+    /// constraints as [`UpdateResults::update_packet`]
+    pub fn apply_packet<R: Read + Seek, W: Write>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+        options: UpdateOptions,
+    ) -> Result<(), XmpError> {
+        let packet = main_packet(reader)?;
+        let xml = packet.read_xml(reader)?;
+        let xml = self.update_xml(Cursor::new(xml), options)?;
+        let data = packet.encode(&xml)?;
+
+        reader.seek(SeekFrom::Start(0))?;
+        std::io::copy(&mut reader.take(packet.offset), writer)?;
+        writer.write_all(&data)?;
+        reader.seek(SeekFrom::Start(packet.offset + packet.length))?;
+        std::io::copy(reader, writer)?;
+        Ok(())
+    }
+}
+
+impl OptionalResults {
+    pub fn load_packet(path: impl AsRef<Path>) -> Result<Self, XmpError> {
+        Self::read_packet(&mut BufReader::new(std::fs::File::open(path)?))
+    }
+
+    pub fn read_packet<R: Read + Seek>(reader: &mut R) -> Result<Self, XmpError> {
+        let xml = main_packet(reader)?.read_xml(reader)?;
+        Self::from_reader(Cursor::new(xml))
+    }
+}
+
+/// `xml` wrapped in a packet with `padding` spaces, between filler standing in for the rest of
+/// the file
+#[cfg(test)]
+fn sample_packet(encoding: PacketEncoding, xml: &str, padding: usize, end: &str) -> Vec<u8> {
+    let packet = format!(
+        "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>{}{}<?xpacket end={}?>",
+        xml,
+        " ".repeat(padding),
+        end
+    );
+    encoding.encode(&packet)
+}
+
+#[test]
+pub fn packet_scan() {
+    // The first header straddles two reads
+    let mut data = vec![b'%'; CHUNK - 7];
+    let mut expected = Vec::new();
+    for (encoding, end) in ENCODINGS
+        .iter()
+        .zip(["'w'", "\"w\"", "'r'", "'w'", "\"w\""])
+    {
+        let packet = sample_packet(*encoding, DEFAULT_XML, 64, end);
+        expected.push(Packet {
+            offset: data.len() as u64,
+            length: packet.len() as u64,
+            encoding: *encoding,
+            writable: end.contains('w'),
+        });
+        data.extend(packet);
+        data.extend_from_slice(b"%%EOF\n\x00\x01");
+    }
+    // A header without a trailer isn't a packet
+    data.extend_from_slice(b"<?xpacket begin='' ?>");
+    let packets = scan(Cursor::new(&data)).unwrap();
+    assert_eq!(packets, expected);
+
+    for packet in packets {
+        let xml = packet.read_xml(&mut Cursor::new(&data)).unwrap();
+        assert_eq!(xml, DEFAULT_XML.trim().as_bytes());
+    }
+}
+
+#[test]
+pub fn packet_rewrite() {
+    let xml = "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"/>";
+    for encoding in ENCODINGS {
+        let mut data = b"%!PS-Adobe-3.0\n".to_vec();
+        data.extend(sample_packet(encoding, xml, 200, "'w'"));
+        data.extend_from_slice(b"\n%%EOF\n");
+        let packet = scan(Cursor::new(&data)).unwrap()[0];
+
+        let mut file = Cursor::new(data.clone());
+        let updated = "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"><rdf:RDF/></x:xmpmeta>";
+        packet.rewrite(&mut file, updated.as_bytes()).unwrap();
+        let file = file.into_inner();
+        assert_eq!(file.len(), data.len());
+        assert!(file.ends_with(b"\n%%EOF\n"));
+        let packets = scan(Cursor::new(&file)).unwrap();
+        assert_eq!(packets, [packet]);
+        assert_eq!(
+            packet.read_xml(&mut Cursor::new(&file)).unwrap(),
+            updated.as_bytes()
+        );
+
+        // Larger than the padding allows
+        let large = format!("<x:xmpmeta>{}</x:xmpmeta>", " ".repeat(400));
+        assert!(packet.encode(large.as_bytes()).is_err());
+    }
+    let readonly = sample_packet(PacketEncoding::Utf8, xml, 200, "'r'");
+    let packet = scan(Cursor::new(&readonly)).unwrap()[0];
+    assert!(packet.encode(xml.as_bytes()).is_err());
+}
+
+#[test]
+pub fn packet_update() {
+    let dir = std::env::temp_dir().join("xmp_packet_update");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("file.eps");
+    let mut data = b"%!PS-Adobe-3.0 EPSF-3.0\n".to_vec();
+    data.extend(sample_packet(
+        PacketEncoding::Utf8,
+        DEFAULT_XML,
+        2048,
+        "'w'",
+    ));
+    data.extend_from_slice(b"\n%%EOF\n");
+    std::fs::write(&path, &data).unwrap();
+
+    let u = UpdateResults {
+        stars: Some(5),
+        colors: Some(String::from("Green")),
+        ..Default::default()
+    };
+    u.update(&path).unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), data.len() as u64);
+    let r = OptionalResults::load(&path).unwrap();
+    assert_eq!(r.stars, Some(5));
+    assert_eq!(r.colors.as_deref(), Some("Green"));
+}
+
+#[test]
+pub fn packet_apply_to_bytes() {
+    let mut original = b"%!PS-Adobe-3.0 EPSF-3.0\n".to_vec();
+    original.extend(sample_packet(
+        PacketEncoding::Utf8,
+        DEFAULT_XML,
+        2048,
+        "'w'",
+    ));
+    original.extend_from_slice(b"\n%%EOF\n");
+
+    let u = UpdateResults {
+        stars: Some(1),
+        ..Default::default()
+    };
+    let data = u.apply_to_bytes(ImageType::Others, &original).unwrap();
+    assert_eq!(data.len(), original.len());
+    assert!(data.ends_with(b"\n%%EOF\n"));
+    let r = OptionalResults::from_bytes(ImageType::Others, &data).unwrap();
+    assert_eq!(r.stars, Some(1));
+}
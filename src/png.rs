@@ -1,15 +1,75 @@
 use crate::*;
 use exif::{Context, In, Tag};
+use img_parts::png::{Png, PngChunk};
 use std::io::Cursor;
 
-pub(crate) fn __png_load_xml(path: impl AsRef<Path>) -> Result<Vec<u8>, XmpError> {
+/// Keyword of the iTXt chunk holding the xmp packet as per the XMP spec part 3
+const XMP_KEYWORD: &[u8] = b"XML:com.adobe.xmp";
+const ITXT: [u8; 4] = *b"iTXt";
+const IDAT: [u8; 4] = *b"IDAT";
+
+/// Returns the text of an iTXt chunk if its keyword is `XML:com.adobe.xmp`
+///
+/// iTXt layout is
+/// keyword \0 compression_flag compression_method language_tag \0 translated_keyword \0 text
+fn itxt_xmp(contents: &[u8]) -> Result<Option<Vec<u8>>, XmpError> {
+    let mut fields = contents.splitn(2, |b| *b == 0);
+    if fields.next() != Some(XMP_KEYWORD) {
+        return Ok(None);
+    }
+    let rest = fields.next().otor(|| XmpErrorKind::InvalidITxt)?;
+    if rest.len() < 2 {
+        return Err(XmpErrorKind::InvalidITxt.into());
+    }
+    let (compression_flag, compression_method) = (rest[0], rest[1]);
+
+    // Skip the language tag and the translated keyword
+    let text = rest[2..]
+        .splitn(3, |b| *b == 0)
+        .nth(2)
+        .otor(|| XmpErrorKind::InvalidITxt)?;
+
+    match (compression_flag, compression_method) {
+        (0, _) => Ok(Some(text.to_vec())),
+        // The only compression method defined is zlib
+        (1, 0) => miniz_oxide::inflate::decompress_to_vec_zlib(text)
+            .map(Some)
+            .map_err(|_| XmpErrorKind::InvalidITxt.into()),
+        _ => Err(XmpErrorKind::InvalidITxt.into()),
+    }
+}
+
+/// Creates an uncompressed iTXt chunk with no language tag or translated keyword
+fn xmp_itxt(xml: &[u8]) -> PngChunk {
+    let mut contents = Vec::with_capacity(XMP_KEYWORD.len() + 5 + xml.len());
+    contents.extend_from_slice(XMP_KEYWORD);
+    // null separator, compression flag, compression method
+    contents.extend_from_slice(&[0, 0, 0]);
+    // empty language tag and translated keyword
+    contents.extend_from_slice(&[0, 0]);
+    contents.extend_from_slice(xml);
+    PngChunk::new(ITXT, contents.into())
+}
+
+fn xmp_chunk_index(png: &Png) -> Result<Option<usize>, XmpError> {
+    for (index, chunk) in png.chunks().iter().enumerate() {
+        if chunk.kind() == ITXT && itxt_xmp(chunk.contents())?.is_some() {
+            return Ok(Some(index));
+        }
+    }
+    Ok(None)
+}
+
+pub(crate) fn __png_xml(png: &Png) -> Result<Vec<u8>, XmpError> {
     use img_parts::ImageEXIF;
-    // First open a buffered reader
-    // Check if the file has a exif header or a jfif header
-    // Its possible for files to have a exif header while also having a jfif header
-    // So check for the exif header first
-    let png = img_parts::png::Png::from_bytes(std::fs::read(&path)?.into())?;
 
+    for chunk in png.chunks_by_type(ITXT) {
+        if let Some(xml) = itxt_xmp(chunk.contents())? {
+            return Ok(xml);
+        }
+    }
+
+    // Fallback to the xmp tag in the eXIf chunk if there's no iTXt chunk
     if let Some(exif_data) = png.exif() {
         let exifreader = exif::Reader::new();
         let exif = exifreader.read_raw(exif_data.to_vec())?;
@@ -21,11 +81,24 @@ pub(crate) fn __png_load_xml(path: impl AsRef<Path>) -> Result<Vec<u8>, XmpError
         }
     }
 
-    let xml = png
-        .chunk_by_type(*b"iTXt")
-        .ok_or_else(|| XmpError::from(XmpErrorKind::XMPMissing))?;
+    Err(XmpErrorKind::XMPMissing.into())
+}
 
-    Ok(xml.contents().to_vec())
+/// Replaces the existing xmp chunk or adds one
+fn __png_write_xml(png: &mut Png, xml: &[u8]) -> Result<(), XmpError> {
+    let chunk = xmp_itxt(xml);
+    if let Some(index) = xmp_chunk_index(png)? {
+        png.chunks_mut()[index] = chunk;
+    } else {
+        // Readers expect the packet before the image data
+        let index = png
+            .chunks()
+            .iter()
+            .position(|chunk| chunk.kind() == IDAT)
+            .otor(|| XmpErrorKind::InvalidFileType)?;
+        png.chunks_mut().insert(index, chunk);
+    }
+    Ok(())
 }
 
 impl UpdateResults {
@@ -34,26 +107,143 @@ impl UpdateResults {
         path: impl AsRef<Path>,
         options: UpdateOptions,
     ) -> Result<(), XmpError> {
-        let xml = __png_load_xml(&path).unwrap_or_else(|_e| DEFAULT_XML.as_bytes().to_vec());
-        // let xml = self.update_xml(Cursor::new(xml))?;
+        let mut reader = BufReader::new(std::fs::File::open(&path)?);
+        replace_file(&path, |writer| self.apply_png(&mut reader, writer, options))
+    }
+
+    pub fn apply_png<R: Read, W: Write>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+        options: UpdateOptions,
+    ) -> Result<(), XmpError> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        let mut png = Png::from_bytes(data.into())?;
+
+        let xml = __png_xml(&png).unwrap_or_else(|_e| DEFAULT_XML.as_bytes().to_vec());
         let xml = self.update_xml(Cursor::new(xml), options)?;
+        __png_write_xml(&mut png, &xml)?;
 
-        let mut png = img_parts::png::Png::from_bytes(std::fs::read(&path)?.into())?;
+        png.encoder().write_to(writer)?;
+        Ok(())
+    }
+}
 
-        for chunk in png.chunks_mut() {
-            if &chunk.kind() == b"iTXt" {
-                *chunk = img_parts::png::PngChunk::new(*b"iTXt", xml.into());
-                break;
-            }
-        }
+pub(crate) struct PngContainer;
+
+impl container::XmpContainer for PngContainer {
+    fn name(&self) -> &str {
+        "png"
+    }
+
+    fn detect(&self, path: &Path, magic: &[u8]) -> bool {
+        ImageType::from_sniffed(path, magic) == ImageType::Png
+    }
 
+    fn extract(&self, reader: &mut dyn container::ReadSeek) -> Result<Vec<u8>, XmpError> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        __png_xml(&Png::from_bytes(data.into())?)
+    }
+
+    fn replace(
+        &self,
+        reader: &mut dyn container::ReadSeek,
+        writer: &mut dyn Write,
+        xml: &[u8],
+        _options: &UpdateOptions,
+    ) -> Result<(), XmpError> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        let mut png = Png::from_bytes(data.into())?;
+        __png_write_xml(&mut png, xml)?;
+        png.encoder().write_to(writer)?;
         Ok(())
     }
 }
 
 impl OptionalResults {
     pub fn load_png(path: impl AsRef<Path>) -> Result<Self, XmpError> {
-        let data = Cursor::new(__png_load_xml(path)?);
-        Self::from_reader(data)
+        Self::read_png(&mut BufReader::new(std::fs::File::open(path)?))
+    }
+
+    pub fn read_png<R: Read>(reader: &mut R) -> Result<Self, XmpError> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        let png = Png::from_bytes(data.into())?;
+        Self::from_reader(Cursor::new(__png_xml(&png)?))
     }
 }
+
+#[test]
+pub fn png_update() {
+    let dir = std::env::temp_dir().join("xmp_png_update");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("1.png");
+    std::fs::copy("assets/1.png", &path).unwrap();
+
+    let u = UpdateResults {
+        stars: Some(2),
+        colors: Some(String::from("Green")),
+        ..Default::default()
+    };
+    u.update(&path).unwrap();
+    let r = OptionalResults::load(&path).unwrap();
+    assert_eq!(r.stars, Some(2));
+    assert_eq!(r.colors.as_deref(), Some("Green"));
+
+    let kinds = |png: &Png| png.chunks().iter().map(|c| c.kind()).collect::<Vec<_>>();
+    let original = Png::from_bytes(std::fs::read("assets/1.png").unwrap().into()).unwrap();
+    let updated = Png::from_bytes(std::fs::read(&path).unwrap().into()).unwrap();
+    assert_eq!(kinds(&original), kinds(&updated));
+}
+
+#[test]
+pub fn png_insert_itxt() {
+    let dir = std::env::temp_dir().join("xmp_png_insert_itxt");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("1.png");
+    let mut png = Png::from_bytes(std::fs::read("assets/1.png").unwrap().into()).unwrap();
+    png.remove_chunks_by_type(ITXT);
+    png.encoder()
+        .write_to(std::fs::File::create(&path).unwrap())
+        .unwrap();
+
+    let u = UpdateResults {
+        stars: Some(1),
+        ..Default::default()
+    };
+    u.update(&path).unwrap();
+    assert_eq!(OptionalResults::load(&path).unwrap().stars, Some(1));
+
+    let png = Png::from_bytes(std::fs::read(&path).unwrap().into()).unwrap();
+    let itxt = png.chunks().iter().position(|c| c.kind() == ITXT).unwrap();
+    let idat = png.chunks().iter().position(|c| c.kind() == IDAT).unwrap();
+    assert_eq!(itxt + 1, idat);
+}
+
+#[cfg(unix)]
+#[test]
+pub fn png_update_keeps_file() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = std::env::temp_dir().join("xmp_png_update_keeps_file");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("1.png");
+    std::fs::copy("assets/1.png", &path).unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640)).unwrap();
+    // A sibling with the same stem must survive the update
+    std::fs::write(dir.join("1.temp"), b"other").unwrap();
+
+    let u = UpdateResults {
+        stars: Some(4),
+        ..Default::default()
+    };
+    u.update(&path).unwrap();
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o640);
+    assert_eq!(std::fs::read(dir.join("1.temp")).unwrap(), b"other");
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+}
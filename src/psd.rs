@@ -0,0 +1,286 @@
+use crate::*;
+use std::io::Cursor;
+
+/// Length of the file header up to the color mode data section
+const HEADER_LEN: usize = 26;
+/// Image resource holding the xmp packet
+const XMP_RESOURCE: u16 = 1060;
+/// Signatures image resource blocks may start with
+const SIGNATURES: [&[u8; 4]; 5] = [b"8BIM", b"MeSa", b"PHUT", b"AgHg", b"DCSR"];
+
+/// A block of the image resources section, borrowed with its signature and padding
+#[derive(Debug, Clone, Copy)]
+struct Resource<'a> {
+    id: u16,
+    data: &'a [u8],
+}
+
+impl Resource<'_> {
+    fn body(&self) -> &[u8] {
+        let name_len = self.data[6] as usize;
+        let offset = 6 + ((name_len + 2) & !1);
+        let size = &self.data[offset..offset + 4];
+        let size = u32::from_be_bytes([size[0], size[1], size[2], size[3]]) as usize;
+        &self.data[offset + 4..offset + 4 + size]
+    }
+}
+
+/// Splits the image resources section into its blocks
+fn resources(section: &[u8]) -> Result<Vec<Resource<'_>>, XmpError> {
+    let mut out = Vec::new();
+    let mut offset = 0;
+    while offset < section.len() {
+        let start = offset;
+        let signature = section
+            .get(offset..offset + 4)
+            .otor(|| XmpErrorKind::InvalidPsd)?;
+        if !SIGNATURES.iter().any(|s| &s[..] == signature) {
+            return Err(XmpErrorKind::InvalidPsd.into());
+        }
+        let id = section
+            .get(offset + 4..offset + 6)
+            .otor(|| XmpErrorKind::InvalidPsd)?;
+        let id = u16::from_be_bytes([id[0], id[1]]);
+        // Pascal string name padded to an even length including the length byte
+        let name_len = *section.get(offset + 6).otor(|| XmpErrorKind::InvalidPsd)? as usize;
+        offset += 6 + ((name_len + 2) & !1);
+        let size = section
+            .get(offset..offset + 4)
+            .otor(|| XmpErrorKind::InvalidPsd)?;
+        let size = u32::from_be_bytes([size[0], size[1], size[2], size[3]]) as usize;
+        offset += 4 + ((size + 1) & !1);
+        let data = section
+            .get(start..offset)
+            .otor(|| XmpErrorKind::InvalidPsd)?;
+        out.push(Resource { id, data });
+    }
+    Ok(out)
+}
+
+fn encode_resource(id: u16, body: &[u8]) -> Result<Vec<u8>, XmpError> {
+    let size = u32::try_from(body.len()).map_err(|_| XmpErrorKind::InvalidPsd)?;
+    let mut out = Vec::with_capacity(body.len() + 13);
+    out.extend_from_slice(b"8BIM");
+    out.extend_from_slice(&id.to_be_bytes());
+    // Empty name
+    out.extend_from_slice(&[0, 0]);
+    out.extend_from_slice(&size.to_be_bytes());
+    out.extend_from_slice(body);
+    if body.len() % 2 == 1 {
+        out.push(0);
+    }
+    Ok(out)
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32, XmpError> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+/// Reads the header, the color mode data and the image resources section, leaving `reader` at
+/// the layer and mask information which is never loaded
+fn read_sections<R: Read>(reader: &mut R) -> Result<(Vec<u8>, Vec<u8>), XmpError> {
+    let mut header = vec![0; HEADER_LEN];
+    reader.read_exact(&mut header)?;
+    // Version 1 is PSD and version 2 PSB, which only differ after the image resources
+    if !header.starts_with(b"8BPS") || !matches!(header[4..6], [0, 1] | [0, 2]) {
+        return Err(XmpErrorKind::InvalidPsd.into());
+    }
+    let color_mode = read_u32(reader)?;
+    header.extend_from_slice(&color_mode.to_be_bytes());
+    let copied = reader.take(color_mode.into()).read_to_end(&mut header)?;
+    if copied != color_mode as usize {
+        return Err(XmpErrorKind::InvalidPsd.into());
+    }
+
+    let len = read_u32(reader)?;
+    // The length is untrusted, `take` limits the read to the bytes actually present instead
+    let mut section = Vec::new();
+    if reader.take(len.into()).read_to_end(&mut section)? != len as usize {
+        return Err(XmpErrorKind::InvalidPsd.into());
+    }
+    Ok((header, section))
+}
+
+pub(crate) fn __psd_xml<R: Read>(reader: &mut R) -> Result<Vec<u8>, XmpError> {
+    let (_, section) = read_sections(reader)?;
+    resources(&section)?
+        .iter()
+        .find(|r| r.id == XMP_RESOURCE)
+        .map(|r| r.body().to_vec())
+        .otor(|| XmpErrorKind::XMPMissing)
+        .map_err(Into::into)
+}
+
+/// Writes the file with the xmp resource replaced by one holding `xml`
+///
+/// Only the image resources are rewritten, the layers and the image data are streamed through
+/// untouched.
+pub(crate) fn __psd_write_xml<R: Read, W: Write>(
+    reader: &mut R,
+    xml: &[u8],
+    writer: &mut W,
+) -> Result<(), XmpError> {
+    let (header, section) = read_sections(reader)?;
+    let mut out = Vec::with_capacity(section.len() + xml.len() + 16);
+    let mut written = false;
+    for resource in resources(&section)? {
+        if resource.id != XMP_RESOURCE {
+            out.extend_from_slice(resource.data);
+        } else if !written {
+            out.extend(encode_resource(XMP_RESOURCE, xml)?);
+            written = true;
+        }
+    }
+    if !written {
+        out.extend(encode_resource(XMP_RESOURCE, xml)?);
+    }
+    let len = u32::try_from(out.len()).map_err(|_| XmpErrorKind::InvalidPsd)?;
+
+    writer.write_all(&header)?;
+    writer.write_all(&len.to_be_bytes())?;
+    writer.write_all(&out)?;
+    std::io::copy(reader, writer)?;
+    Ok(())
+}
+
+impl UpdateResults {
+    pub fn update_psd(
+        &self,
+        path: impl AsRef<Path>,
+        options: UpdateOptions,
+    ) -> Result<(), XmpError> {
+        let mut reader = BufReader::new(std::fs::File::open(&path)?);
+        replace_file(&path, |writer| self.apply_psd(&mut reader, writer, options))
+    }
+
+    pub fn apply_psd<R: Read + Seek, W: Write>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+        options: UpdateOptions,
+    ) -> Result<(), XmpError> {
+        let xml = __psd_xml(reader).unwrap_or_else(|_e| DEFAULT_XML.as_bytes().to_vec());
+        let xml = self.update_xml(Cursor::new(xml), options)?;
+        reader.seek(SeekFrom::Start(0))?;
+        __psd_write_xml(reader, &xml, writer)
+    }
+}
+
+impl OptionalResults {
+    pub fn load_psd(path: impl AsRef<Path>) -> Result<Self, XmpError> {
+        Self::read_psd(&mut BufReader::new(std::fs::File::open(path)?))
+    }
+
+    pub fn read_psd<R: Read>(reader: &mut R) -> Result<Self, XmpError> {
+        Self::from_reader(Cursor::new(__psd_xml(reader)?))
+    }
+}
+
+/// A 1x1 RGB image with a resolution resource, a named resource with an odd length and a layer
+/// section standing in for the layers and channels
+#[cfg(test)]
+fn sample_psd(version: u16) -> Vec<u8> {
+    let resolution = encode_resource(1005, &[0; 16]).unwrap();
+    let named = [
+        &b"8BIM"[..],
+        &4000u16.to_be_bytes(),
+        &[3],
+        b"abc",
+        &3u32.to_be_bytes(),
+        b"odd",
+        &[0],
+    ]
+    .concat();
+    let section = [resolution, named].concat();
+    let layers = if version == 1 {
+        [&8u32.to_be_bytes()[..], b"layers!!"].concat()
+    } else {
+        [&8u64.to_be_bytes()[..], b"layers!!"].concat()
+    };
+    [
+        &b"8BPS"[..],
+        &version.to_be_bytes(),
+        &[0; 6],
+        &3u16.to_be_bytes(),
+        &1u32.to_be_bytes(),
+        &1u32.to_be_bytes(),
+        &8u16.to_be_bytes(),
+        &3u16.to_be_bytes(),
+        &0u32.to_be_bytes(),
+        &(section.len() as u32).to_be_bytes(),
+        &section,
+        &layers,
+        // Raw image data
+        &[0, 0, 0xff, 0x80, 0x40],
+    ]
+    .concat()
+}
+
+#[test]
+pub fn psd_write_xml() {
+    for version in [1, 2] {
+        let original = sample_psd(version);
+        let mut data = Vec::new();
+        __psd_write_xml(
+            &mut Cursor::new(&original),
+            DEFAULT_XML.as_bytes(),
+            &mut data,
+        )
+        .unwrap();
+        assert_eq!(
+            __psd_xml(&mut Cursor::new(&data)).unwrap(),
+            DEFAULT_XML.as_bytes()
+        );
+
+        let (header, section) = read_sections(&mut Cursor::new(&data)).unwrap();
+        assert_eq!(header, original[..HEADER_LEN + 4]);
+        let ids: Vec<_> = resources(&section).unwrap().iter().map(|r| r.id).collect();
+        assert_eq!(ids, [1005, 4000, XMP_RESOURCE]);
+        assert_eq!(resources(&section).unwrap()[1].body(), b"odd");
+        // The layers and the image data follow the rewritten section untouched
+        let tail = original.len() - (if version == 1 { 12 } else { 16 }) - 5;
+        assert!(data.ends_with(&original[tail..]));
+
+        // The packet is replaced instead of added again
+        let mut updated = Vec::new();
+        __psd_write_xml(&mut Cursor::new(&data), b"<x:xmpmeta/>", &mut updated).unwrap();
+        assert_eq!(
+            __psd_xml(&mut Cursor::new(&updated)).unwrap(),
+            b"<x:xmpmeta/>"
+        );
+        let (_, section) = read_sections(&mut Cursor::new(&updated)).unwrap();
+        assert_eq!(resources(&section).unwrap().len(), 3);
+        assert!(updated.ends_with(&original[tail..]));
+    }
+}
+
+#[test]
+pub fn psd_truncated() {
+    let mut data = sample_psd(1);
+    data.truncate(HEADER_LEN + 4);
+    data.extend_from_slice(&u32::MAX.to_be_bytes());
+    data.extend_from_slice(b"8BIM");
+    assert!(__psd_xml(&mut Cursor::new(&data)).is_err());
+}
+
+#[test]
+pub fn psd_update() {
+    let dir = std::env::temp_dir().join("xmp_psd_update");
+    std::fs::create_dir_all(&dir).unwrap();
+    for (name, version) in [("file.psd", 1), ("file.psb", 2)] {
+        let path = dir.join(name);
+        std::fs::write(&path, sample_psd(version)).unwrap();
+
+        let u = UpdateResults {
+            stars: Some(4),
+            colors: Some(String::from("Blue")),
+            ..Default::default()
+        };
+        u.update(&path).unwrap();
+        let r = OptionalResults::load(&path).unwrap();
+        assert_eq!(r.stars, Some(4));
+        assert_eq!(r.colors.as_deref(), Some("Blue"));
+    }
+}
@@ -1,7 +1,110 @@
+use crate::isobmff::{read_uuid, XMP_UUID};
+use crate::tiff::{Header, XMP_TAG};
 use crate::*;
+use std::collections::VecDeque;
 use std::io::Cursor;
 
-pub(crate) fn __raw_load_xml(path: impl AsRef<Path>) -> Result<Vec<u8>, XmpError> {
+/// Tags pointing to the SubIFDs and the Exif IFD which are searched after IFD0
+const SUB_IFDS: u16 = 330;
+const EXIF_IFD: u16 = 34665;
+/// Upper bound on the IFDs visited so a looping chain can't hang the walk
+const MAX_IFDS: usize = 256;
+/// Upper bound on the size of a packet found by scanning
+const MAX_PACKET: usize = 16 << 20;
+
+/// Walks IFD0, the IFD chain, the SubIFDs and the Exif IFD looking for tag 700
+fn __ifd_xml<R: Read + Seek>(reader: &mut R) -> Result<Vec<u8>, XmpError> {
+    let header = Header::read(reader)?;
+    let mut queue = VecDeque::from([header.ifd0]);
+    let mut seen = HashSet::new();
+    while let Some(offset) = queue.pop_front() {
+        if offset == 0 || !seen.insert(offset) || seen.len() > MAX_IFDS {
+            continue;
+        }
+        let ifd = match header.read_ifd(reader, offset) {
+            Ok(ifd) => ifd,
+            // Makers sometimes point to IFDs in their own formats
+            Err(_) => continue,
+        };
+        if let Some(entry) = ifd.entries.iter().find(|e| e.tag == XMP_TAG) {
+            return header.read_data(reader, entry);
+        }
+        queue.push_back(ifd.next);
+        for entry in ifd
+            .entries
+            .iter()
+            .filter(|e| e.tag == SUB_IFDS || e.tag == EXIF_IFD)
+        {
+            if let Ok(offsets) = header.read_offsets(reader, entry) {
+                queue.extend(offsets);
+            }
+        }
+    }
+    Err(XmpErrorKind::XMPMissing.into())
+}
+
+/// Scans the file for a serialized `x:xmpmeta` element
+fn __scan_xml<R: Read>(mut reader: R) -> Result<Vec<u8>, XmpError> {
+    const START: &[u8] = b"<x:xmpmeta";
+    const END: &[u8] = b"</x:xmpmeta>";
+    let find = |haystack: &[u8], needle: &[u8]| {
+        haystack
+            .windows(needle.len())
+            .position(|window| window == needle)
+    };
+
+    let mut buffer = Vec::new();
+    let mut chunk = vec![0; 1 << 16];
+    let mut found = false;
+    loop {
+        let read = reader.read(&mut chunk)?;
+        if read == 0 {
+            return Err(XmpErrorKind::XMPMissing.into());
+        }
+        // Only search the new bytes and the ones a match could straddle
+        let from = buffer
+            .len()
+            .saturating_sub(if found { END.len() } else { START.len() });
+        buffer.extend_from_slice(&chunk[..read]);
+        if !found {
+            match find(&buffer[from..], START) {
+                Some(start) => {
+                    buffer.drain(..from + start);
+                    found = true;
+                }
+                None => {
+                    buffer.drain(..buffer.len().saturating_sub(START.len()));
+                    continue;
+                }
+            }
+        }
+        if let Some(end) = find(&buffer[from.min(buffer.len())..], END) {
+            buffer.truncate(from.min(buffer.len()) + end + END.len());
+            return Ok(buffer);
+        }
+        if buffer.len() > MAX_PACKET {
+            return Err(XmpErrorKind::XMPMissing.into());
+        }
+    }
+}
+
+/// Reads the xmp packet of tiff based raws and CR3 without libraw
+pub(crate) fn __raw_xml<R: Read + Seek>(reader: &mut R) -> Result<Vec<u8>, XmpError> {
+    let mut magic = [0; 8];
+    reader.seek(SeekFrom::Start(0))?;
+    reader.read_exact(&mut magic)?;
+    if &magic[4..] == b"ftyp" {
+        return read_uuid(reader, &XMP_UUID);
+    }
+    if let Ok(xml) = __ifd_xml(reader) {
+        return Ok(xml);
+    }
+    reader.seek(SeekFrom::Start(0))?;
+    __scan_xml(reader)
+}
+
+#[cfg(feature = "raw")]
+pub(crate) fn __libraw_load_xml(path: impl AsRef<Path>) -> Result<Vec<u8>, XmpError> {
     let mut processor = libraw_r::Processor::default();
     let exif = processor.set_exif_callback(
         Vec::<u8>::new(),
@@ -22,10 +125,147 @@ pub(crate) fn __raw_load_xml(path: impl AsRef<Path>) -> Result<Vec<u8>, XmpError
     Ok(xmp.to_vec())
 }
 
+pub(crate) fn __raw_load_xml(path: impl AsRef<Path>) -> Result<Vec<u8>, XmpError> {
+    let xml = __raw_xml(&mut BufReader::new(std::fs::File::open(&path)?));
+    // libraw knows about more maker specific layouts
+    #[cfg(feature = "raw")]
+    if xml.is_err() {
+        return __libraw_load_xml(path);
+    }
+    xml
+}
+
 impl OptionalResults {
+    /// Reads the packet embedded in the raw, without falling back to libraw which needs a path
+    pub fn read_raw<R: Read + Seek>(reader: &mut R) -> Result<Self, XmpError> {
+        Self::from_reader(Cursor::new(__raw_xml(reader)?))
+    }
+
     pub fn load_raw(path: impl AsRef<Path>) -> Result<Self, XmpError> {
         let data = Cursor::new(__raw_load_xml(&path)?);
         // std::fs::write("somefile.xmp", &__raw_load_xml(&path)?)?;
         Self::from_reader(data)
     }
 }
+
+/// Raws are never rewritten, updates go to the sidecar next to them or to tag 700 of dngs
+pub(crate) struct RawContainer;
+
+impl container::XmpContainer for RawContainer {
+    fn name(&self) -> &str {
+        "raw"
+    }
+
+    fn detect(&self, path: &Path, magic: &[u8]) -> bool {
+        ImageType::from_sniffed(path, magic) == ImageType::Raw
+    }
+
+    fn extract(&self, mut reader: &mut dyn container::ReadSeek) -> Result<Vec<u8>, XmpError> {
+        __raw_xml(&mut reader)
+    }
+
+    fn replace(
+        &self,
+        _reader: &mut dyn container::ReadSeek,
+        _writer: &mut dyn Write,
+        _xml: &[u8],
+        _options: &UpdateOptions,
+    ) -> Result<(), XmpError> {
+        Err(XmpErrorKind::InvalidFileType.into())
+    }
+
+    /// Prefers the sidecar unless it was written for another raw of the same name
+    fn load(&self, path: &Path) -> Result<OptionalResults, XmpError> {
+        let raw_ext = path.extension().and_then(OsStr::to_str);
+        if let Some(path) = exists_with_extension(path, "xmp") {
+            let xmp = OptionalResults::load_xmp(&path);
+            if let Ok(UpdateResults {
+                sidecar_for_extension: Some(ref sidecar_for_extension),
+                ..
+            }) = xmp
+            {
+                if let Some(ext) = raw_ext {
+                    if sidecar_for_extension.eq_ignore_ascii_case(ext) {
+                        return xmp;
+                    }
+                }
+            } else {
+                return xmp;
+            }
+        }
+        OptionalResults::load_raw(path)
+    }
+
+    fn update(
+        &self,
+        results: &UpdateResults,
+        path: &Path,
+        options: UpdateOptions,
+    ) -> Result<(), XmpError> {
+        #[cfg(feature = "tiff")]
+        if options.embed_dng && is_dng(path) {
+            results.update_tiff(path, options.clone())?;
+            // Loading prefers the sidecar, it would hide the embedded packet if left behind
+            if let Some(path) = exists_with_extension(path, "xmp") {
+                results.update_xmp(path, options)?;
+            }
+            return Ok(());
+        }
+        if let Some(path) = exists_with_extension(path, "xmp") {
+            results.update_xmp(path, options)
+        } else if options.create_sidecar {
+            results.create_sidecar(path, options)
+        } else {
+            Err(XmpError::from(XmpErrorKind::InvalidFileType))
+        }
+    }
+}
+
+#[test]
+pub fn raw_ifd_xml() {
+    use crate::tiff::{__tiff_write_xml, sample_tiff, ByteOrder};
+
+    let mut nef = Cursor::new(sample_tiff(ByteOrder::Big, false));
+    __tiff_write_xml(&mut nef, DEFAULT_XML.as_bytes()).unwrap();
+    assert_eq!(__raw_xml(&mut nef).unwrap(), DEFAULT_XML.as_bytes());
+
+    // Olympus ORF magic
+    let mut orf = Cursor::new(sample_tiff(ByteOrder::Little, false));
+    orf.get_mut()[2..4].copy_from_slice(b"RO");
+    __tiff_write_xml(&mut orf, DEFAULT_XML.as_bytes()).unwrap();
+    assert_eq!(__raw_xml(&mut orf).unwrap(), DEFAULT_XML.as_bytes());
+}
+
+#[test]
+pub fn raw_scan_xml() {
+    let mut data = sample_tiff_without_xmp();
+    data.extend(vec![0; 70000]);
+    data.extend_from_slice(DEFAULT_XML.as_bytes());
+    data.extend_from_slice(b"trailing");
+    assert_eq!(
+        __raw_xml(&mut Cursor::new(data)).unwrap(),
+        DEFAULT_XML.trim_end().as_bytes()
+    );
+}
+
+#[cfg(test)]
+fn sample_tiff_without_xmp() -> Vec<u8> {
+    crate::tiff::sample_tiff(crate::tiff::ByteOrder::Little, true)
+}
+
+#[test]
+pub fn raw_cr3_xml() {
+    use crate::isobmff::encode_box;
+
+    let data = [
+        encode_box(b"ftyp", b"crx \0\0\0\x01crx isom"),
+        encode_box(b"moov", b"camera data"),
+        encode_box(b"uuid", &[&XMP_UUID[..], DEFAULT_XML.as_bytes()].concat()),
+        encode_box(b"mdat", b"image data"),
+    ]
+    .concat();
+    assert_eq!(
+        __raw_xml(&mut Cursor::new(data)).unwrap(),
+        DEFAULT_XML.as_bytes()
+    );
+}
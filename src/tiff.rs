@@ -0,0 +1,728 @@
+use crate::*;
+#[cfg(feature = "tiff")]
+use std::fs::OpenOptions;
+#[cfg(any(feature = "tiff", test))]
+use std::io::Cursor;
+
+/// Tag holding the xmp packet as per the XMP spec part 3
+pub(crate) const XMP_TAG: u16 = 700;
+/// Field type used for new xmp entries
+const BYTE: u16 = 1;
+/// The other field type xmp entries are written with, kept when an existing entry uses it
+const UNDEFINED: u16 = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ByteOrder {
+    Little,
+    Big,
+}
+
+impl ByteOrder {
+    fn u16(self, b: [u8; 2]) -> u16 {
+        match self {
+            Self::Little => u16::from_le_bytes(b),
+            Self::Big => u16::from_be_bytes(b),
+        }
+    }
+    fn u32(self, b: [u8; 4]) -> u32 {
+        match self {
+            Self::Little => u32::from_le_bytes(b),
+            Self::Big => u32::from_be_bytes(b),
+        }
+    }
+    fn u64(self, b: [u8; 8]) -> u64 {
+        match self {
+            Self::Little => u64::from_le_bytes(b),
+            Self::Big => u64::from_be_bytes(b),
+        }
+    }
+    fn u16_bytes(self, v: u16) -> [u8; 2] {
+        match self {
+            Self::Little => v.to_le_bytes(),
+            Self::Big => v.to_be_bytes(),
+        }
+    }
+    fn u32_bytes(self, v: u32) -> [u8; 4] {
+        match self {
+            Self::Little => v.to_le_bytes(),
+            Self::Big => v.to_be_bytes(),
+        }
+    }
+    fn u64_bytes(self, v: u64) -> [u8; 8] {
+        match self {
+            Self::Little => v.to_le_bytes(),
+            Self::Big => v.to_be_bytes(),
+        }
+    }
+}
+
+/// The image file header of a classic tiff or a BigTIFF
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Header {
+    pub order: ByteOrder,
+    pub big: bool,
+    pub ifd0: u64,
+}
+
+/// A single IFD entry with the value / offset field kept as raw bytes
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Entry {
+    pub tag: u16,
+    pub kind: u16,
+    pub count: u64,
+    /// Only the first 4 bytes are used for classic tiff
+    pub value: [u8; 8],
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Ifd {
+    pub offset: u64,
+    pub entries: Vec<Entry>,
+    pub next: u64,
+}
+
+impl Header {
+    pub fn read<R: Read + Seek>(reader: &mut R) -> Result<Self, XmpError> {
+        reader.seek(SeekFrom::Start(0))?;
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        let order = match &magic[..2] {
+            b"II" => ByteOrder::Little,
+            b"MM" => ByteOrder::Big,
+            _ => return Err(XmpErrorKind::InvalidTiff.into()),
+        };
+        let mut header = Self {
+            order,
+            big: false,
+            ifd0: 0,
+        };
+        match order.u16([magic[2], magic[3]]) {
+            // Panasonic RW2 and Olympus ORF use their own magic with the classic layout
+            42 | 0x55 | 0x4f52 | 0x5352 => (),
+            43 => {
+                header.big = true;
+                // Bytesize of offsets followed by a constant 0
+                let mut bytesize = [0; 4];
+                reader.read_exact(&mut bytesize)?;
+                if order.u16([bytesize[0], bytesize[1]]) != 8 {
+                    return Err(XmpErrorKind::InvalidTiff.into());
+                }
+            }
+            _ => return Err(XmpErrorKind::InvalidTiff.into()),
+        }
+        header.ifd0 = header.read_offset(reader)?;
+        Ok(header)
+    }
+
+    /// Position of the offset to IFD0 in the header
+    fn ifd0_pointer(&self) -> u64 {
+        if self.big {
+            8
+        } else {
+            4
+        }
+    }
+
+    fn offset_size(&self) -> usize {
+        if self.big {
+            8
+        } else {
+            4
+        }
+    }
+
+    fn count_size(&self) -> u64 {
+        if self.big {
+            8
+        } else {
+            2
+        }
+    }
+
+    fn entry_size(&self) -> u64 {
+        if self.big {
+            20
+        } else {
+            12
+        }
+    }
+
+    fn read_u16<R: Read>(&self, reader: &mut R) -> Result<u16, XmpError> {
+        let mut b = [0; 2];
+        reader.read_exact(&mut b)?;
+        Ok(self.order.u16(b))
+    }
+
+    fn read_offset<R: Read>(&self, reader: &mut R) -> Result<u64, XmpError> {
+        if self.big {
+            let mut b = [0; 8];
+            reader.read_exact(&mut b)?;
+            Ok(self.order.u64(b))
+        } else {
+            let mut b = [0; 4];
+            reader.read_exact(&mut b)?;
+            Ok(self.order.u32(b).into())
+        }
+    }
+
+    fn encode_offset(&self, offset: u64) -> Result<[u8; 8], XmpError> {
+        let mut value = [0; 8];
+        if self.big {
+            value = self.order.u64_bytes(offset);
+        } else {
+            let offset = u32::try_from(offset).map_err(|_| XmpErrorKind::InvalidTiff)?;
+            value[..4].copy_from_slice(&self.order.u32_bytes(offset));
+        }
+        Ok(value)
+    }
+
+    pub fn read_ifd<R: Read + Seek>(&self, reader: &mut R, offset: u64) -> Result<Ifd, XmpError> {
+        reader.seek(SeekFrom::Start(offset))?;
+        let count = if self.big {
+            self.read_offset(reader)?
+        } else {
+            self.read_u16(reader)?.into()
+        };
+        // Guard against allocating for a garbage count
+        if count > u16::MAX.into() {
+            return Err(XmpErrorKind::InvalidTiff.into());
+        }
+        let mut entries = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let tag = self.read_u16(reader)?;
+            let kind = self.read_u16(reader)?;
+            let count = if self.big {
+                self.read_offset(reader)?
+            } else {
+                let mut b = [0; 4];
+                reader.read_exact(&mut b)?;
+                self.order.u32(b).into()
+            };
+            let mut value = [0; 8];
+            reader.read_exact(&mut value[..self.offset_size()])?;
+            entries.push(Entry {
+                tag,
+                kind,
+                count,
+                value,
+            });
+        }
+        let next = self.read_offset(reader)?;
+        Ok(Ifd {
+            offset,
+            entries,
+            next,
+        })
+    }
+
+    /// Length in bytes of the data of an entry
+    pub fn data_len(&self, entry: &Entry) -> Result<u64, XmpError> {
+        let size = match entry.kind {
+            1 | 2 | 6 | 7 => 1,
+            3 | 8 => 2,
+            4 | 9 | 11 | 13 => 4,
+            5 | 10 | 12 | 16 | 17 | 18 => 8,
+            _ => return Err(XmpErrorKind::InvalidTiff.into()),
+        };
+        entry
+            .count
+            .checked_mul(size)
+            .otor(|| XmpErrorKind::InvalidTiff)
+            .map_err(Into::into)
+    }
+
+    /// Offset of the data of an entry or `None` if the data is inlined in the entry
+    pub fn data_offset(&self, entry: &Entry) -> Result<Option<u64>, XmpError> {
+        if self.data_len(entry)? <= self.offset_size() as u64 {
+            return Ok(None);
+        }
+        Ok(Some(if self.big {
+            self.order.u64(entry.value)
+        } else {
+            let mut b = [0; 4];
+            b.copy_from_slice(&entry.value[..4]);
+            self.order.u32(b).into()
+        }))
+    }
+
+    pub fn read_data<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        entry: &Entry,
+    ) -> Result<Vec<u8>, XmpError> {
+        let len = self.data_len(entry)?;
+        match self.data_offset(entry)? {
+            None => Ok(entry.value[..len as usize].to_vec()),
+            Some(offset) => {
+                let end = reader.seek(SeekFrom::End(0))?;
+                if !matches!(offset.checked_add(len), Some(e) if e <= end) {
+                    return Err(XmpErrorKind::InvalidTiff.into());
+                }
+                reader.seek(SeekFrom::Start(offset))?;
+                let mut data = vec![0; len as usize];
+                reader.read_exact(&mut data)?;
+                Ok(data)
+            }
+        }
+    }
+
+    /// Reads the data of a LONG, IFD, LONG8 or IFD8 entry holding offsets
+    pub fn read_offsets<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        entry: &Entry,
+    ) -> Result<Vec<u64>, XmpError> {
+        let data = self.read_data(reader, entry)?;
+        match entry.kind {
+            4 | 13 => Ok(data
+                .chunks_exact(4)
+                .map(|c| self.order.u32([c[0], c[1], c[2], c[3]]).into())
+                .collect()),
+            16 | 18 => Ok(data
+                .chunks_exact(8)
+                .map(|c| {
+                    self.order
+                        .u64([c[0], c[1], c[2], c[3], c[4], c[5], c[6], c[7]])
+                })
+                .collect()),
+            _ => Err(XmpErrorKind::InvalidTiff.into()),
+        }
+    }
+
+    fn encode_entry(&self, entry: &Entry) -> Result<Vec<u8>, XmpError> {
+        let mut out = Vec::with_capacity(self.entry_size() as usize);
+        out.extend_from_slice(&self.order.u16_bytes(entry.tag));
+        out.extend_from_slice(&self.order.u16_bytes(entry.kind));
+        if self.big {
+            out.extend_from_slice(&self.order.u64_bytes(entry.count));
+        } else {
+            let count = u32::try_from(entry.count).map_err(|_| XmpErrorKind::InvalidTiff)?;
+            out.extend_from_slice(&self.order.u32_bytes(count));
+        }
+        out.extend_from_slice(&entry.value[..self.offset_size()]);
+        Ok(out)
+    }
+
+    fn encode_ifd(&self, ifd: &Ifd) -> Result<Vec<u8>, XmpError> {
+        let mut out = Vec::new();
+        if self.big {
+            out.extend_from_slice(&self.order.u64_bytes(ifd.entries.len() as u64));
+        } else {
+            let count = u16::try_from(ifd.entries.len()).map_err(|_| XmpErrorKind::InvalidTiff)?;
+            out.extend_from_slice(&self.order.u16_bytes(count));
+        }
+        for entry in &ifd.entries {
+            out.extend(self.encode_entry(entry)?);
+        }
+        out.extend_from_slice(&self.encode_offset(ifd.next)?[..self.offset_size()]);
+        Ok(out)
+    }
+}
+
+/// Reads the xmp packet from tag 700 of IFD0
+pub(crate) fn __tiff_xml<R: Read + Seek>(reader: &mut R) -> Result<Vec<u8>, XmpError> {
+    let header = Header::read(reader)?;
+    let ifd0 = header.read_ifd(reader, header.ifd0)?;
+    let entry = ifd0
+        .entries
+        .iter()
+        .find(|e| e.tag == XMP_TAG)
+        .otor(|| XmpErrorKind::XMPMissing)?;
+    header.read_data(reader, entry)
+}
+
+/// Writes the xmp packet to tag 700 of IFD0
+///
+/// Nothing else in the file is moved, so every other IFD, strip and SubIFD keeps its offset. A
+/// packet fitting in the space of the current one overwrites it, padded with whitespace so the
+/// space keeps its size for later updates. A larger packet overwrites the current one if that is
+/// last in the file and is appended otherwise. If IFD0 has no xmp entry yet a copy of IFD0 with
+/// the new entry is appended and the header is pointed to it, later updates patch that copy.
+pub(crate) fn __tiff_write_xml<F>(file: &mut F, xml: &[u8]) -> Result<(), XmpError>
+where
+    F: Read + Write + Seek,
+{
+    let header = Header::read(file)?;
+    let mut ifd0 = header.read_ifd(file, header.ifd0)?;
+    let existing = ifd0.entries.iter().position(|e| e.tag == XMP_TAG);
+    // Offset and length of the current packet unless it is inlined in the entry
+    let slot = match existing.map(|index| &ifd0.entries[index]) {
+        Some(entry) => match header.data_offset(entry)? {
+            Some(offset) => Some((offset, header.data_len(entry)?)),
+            None => None,
+        },
+        None => None,
+    };
+
+    let end = file.seek(SeekFrom::End(0))?;
+    // Offsets need to be on a word boundary
+    let aligned = end + end % 2;
+
+    let mut packet = xml.to_vec();
+    let mut entry = Entry {
+        tag: XMP_TAG,
+        // The count is in bytes, so the entry has to use a byte sized type
+        kind: match existing.map(|index| ifd0.entries[index].kind) {
+            Some(UNDEFINED) => UNDEFINED,
+            _ => BYTE,
+        },
+        count: 0,
+        value: [0; 8],
+    };
+    // Where the packet is written, `None` if it is inlined in the entry
+    let packet_offset = if xml.len() <= header.offset_size() {
+        entry.value[..xml.len()].copy_from_slice(xml);
+        None
+    } else {
+        let offset = match (existing, slot) {
+            (_, Some((offset, len))) if xml.len() as u64 <= len => {
+                packet.resize(len as usize, b' ');
+                offset
+            }
+            (_, Some((offset, len))) if offset.saturating_add(len) >= end => offset,
+            (Some(_), _) => aligned,
+            // The new IFD0 goes first and the packet after it
+            (None, _) => {
+                let size = header.count_size()
+                    + (ifd0.entries.len() as u64 + 1) * header.entry_size()
+                    + header.offset_size() as u64;
+                aligned + size + size % 2
+            }
+        };
+        entry.value = header.encode_offset(offset)?;
+        Some(offset)
+    };
+    entry.count = packet.len() as u64;
+
+    match existing {
+        Some(index) => {
+            if let Some(offset) = packet_offset {
+                write_at(file, end, offset, &packet)?;
+            }
+            file.flush()?;
+            let position = ifd0.offset + header.count_size() + index as u64 * header.entry_size();
+            file.seek(SeekFrom::Start(position))?;
+            file.write_all(&header.encode_entry(&entry)?)?;
+        }
+        None => {
+            ifd0.entries.push(entry);
+            // Entries must be sorted in ascending order by tag
+            ifd0.entries.sort_by_key(|e| e.tag);
+            let mut encoded = header.encode_ifd(&ifd0)?;
+            if encoded.len() % 2 == 1 {
+                encoded.push(0);
+            }
+            write_at(file, end, aligned, &encoded)?;
+            if let Some(offset) = packet_offset {
+                write_at(file, aligned + encoded.len() as u64, offset, &packet)?;
+            }
+            file.flush()?;
+            file.seek(SeekFrom::Start(header.ifd0_pointer()))?;
+            file.write_all(&header.encode_offset(aligned)?[..header.offset_size()])?;
+        }
+    }
+    file.flush()?;
+    Ok(())
+}
+
+/// Writes `data` at `offset`, zero filling the gap if `offset` is past the current `end`
+fn write_at<W: Write + Seek>(
+    writer: &mut W,
+    end: u64,
+    offset: u64,
+    data: &[u8],
+) -> Result<(), XmpError> {
+    if offset > end {
+        writer.seek(SeekFrom::Start(end))?;
+        writer.write_all(&vec![0; (offset - end) as usize])?;
+    } else {
+        writer.seek(SeekFrom::Start(offset))?;
+    }
+    writer.write_all(data)?;
+    Ok(())
+}
+
+#[cfg(feature = "tiff")]
+impl UpdateResults {
+    pub fn update_tiff(
+        &self,
+        path: impl AsRef<Path>,
+        options: UpdateOptions,
+    ) -> Result<(), XmpError> {
+        let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+        let xml = __tiff_xml(&mut BufReader::new(&mut file))
+            .unwrap_or_else(|_e| DEFAULT_XML.as_bytes().to_vec());
+        let xml = self.update_xml(Cursor::new(xml), options)?;
+        __tiff_write_xml(&mut file, &xml).map_err(|e| e.with_name(path.as_ref()))
+    }
+
+    /// Like [`UpdateResults::update_tiff`] but copies the file to `writer`, which needs the
+    /// whole file in memory since the header is patched after the packet is written
+    pub fn apply_tiff<R: Read, W: Write>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+        options: UpdateOptions,
+    ) -> Result<(), XmpError> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        let mut file = Cursor::new(data);
+        let xml = __tiff_xml(&mut file).unwrap_or_else(|_e| DEFAULT_XML.as_bytes().to_vec());
+        let xml = self.update_xml(Cursor::new(xml), options)?;
+        __tiff_write_xml(&mut file, &xml)?;
+        writer.write_all(file.get_ref())?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "tiff")]
+impl OptionalResults {
+    pub fn load_tiff(path: impl AsRef<Path>) -> Result<Self, XmpError> {
+        Self::read_tiff(&mut BufReader::new(std::fs::File::open(path)?))
+    }
+
+    pub fn read_tiff<R: Read + Seek>(reader: &mut R) -> Result<Self, XmpError> {
+        Self::from_reader(Cursor::new(__tiff_xml(reader)?))
+    }
+}
+
+/// Builds a tiff with a single strip, a SubIFD and a second IFD for the tests
+#[cfg(test)]
+pub(crate) fn sample_tiff(order: ByteOrder, big: bool) -> Vec<u8> {
+    let header = Header {
+        order,
+        big,
+        ifd0: 0,
+    };
+    let mut out = Vec::new();
+    out.extend_from_slice(if order == ByteOrder::Little {
+        b"II"
+    } else {
+        b"MM"
+    });
+    if big {
+        out.extend_from_slice(&order.u16_bytes(43));
+        out.extend_from_slice(&order.u16_bytes(8));
+        out.extend_from_slice(&[0, 0]);
+    } else {
+        out.extend_from_slice(&order.u16_bytes(42));
+    }
+    let pointer = out.len();
+    out.extend_from_slice(&[0; 8][..header.offset_size()]);
+
+    let strip = out.len() as u64;
+    out.extend_from_slice(b"strip data");
+
+    let long = |tag, v: u64| Entry {
+        tag,
+        kind: if big { 16 } else { 4 },
+        count: 1,
+        value: header.encode_offset(v).unwrap(),
+    };
+
+    let sub_ifd = out.len() as u64;
+    let ifd = Ifd {
+        offset: sub_ifd,
+        entries: vec![long(256, 2)],
+        next: 0,
+    };
+    out.extend(header.encode_ifd(&ifd).unwrap());
+
+    let ifd1 = out.len() as u64;
+    let ifd = Ifd {
+        offset: ifd1,
+        entries: vec![long(256, 1)],
+        next: 0,
+    };
+    out.extend(header.encode_ifd(&ifd).unwrap());
+
+    let ifd0 = out.len() as u64;
+    let ifd = Ifd {
+        offset: ifd0,
+        entries: vec![
+            long(256, 1),
+            long(273, strip),
+            long(279, 10),
+            long(330, sub_ifd),
+        ],
+        next: ifd1,
+    };
+    out.extend(header.encode_ifd(&ifd).unwrap());
+    out[pointer..pointer + header.offset_size()]
+        .copy_from_slice(&header.encode_offset(ifd0).unwrap()[..header.offset_size()]);
+    out
+}
+
+#[test]
+pub fn tiff_write_xml() {
+    for (order, big) in [
+        (ByteOrder::Little, false),
+        (ByteOrder::Big, false),
+        (ByteOrder::Little, true),
+        (ByteOrder::Big, true),
+    ] {
+        let original = sample_tiff(order, big);
+        let mut file = Cursor::new(original.clone());
+        assert!(__tiff_xml(&mut file).is_err());
+
+        let xml = DEFAULT_XML.as_bytes();
+        __tiff_write_xml(&mut file, xml).unwrap();
+        assert_eq!(__tiff_xml(&mut file).unwrap(), xml);
+
+        // A shorter packet overwrites the current one, padded to its length
+        let before = file.get_ref().clone();
+        __tiff_write_xml(&mut file, &xml[..100]).unwrap();
+        let written = __tiff_xml(&mut file).unwrap();
+        assert_eq!(written.len(), xml.len());
+        assert_eq!(written[..100], xml[..100]);
+        assert!(written[100..].iter().all(|&b| b == b' '));
+        assert_eq!(file.get_ref().len(), before.len());
+        let header = Header::read(&mut file).unwrap();
+        let ifd0 = header.read_ifd(&mut file, header.ifd0).unwrap();
+        let entry = ifd0.entries.iter().find(|e| e.tag == XMP_TAG).unwrap();
+        assert_eq!(entry.kind, BYTE);
+        let offset = header.data_offset(entry).unwrap().unwrap() as usize;
+        let packet = offset..offset + xml.len();
+        for (i, (a, b)) in before.iter().zip(file.get_ref()).enumerate() {
+            assert!(a == b || packet.contains(&i));
+        }
+
+        let header = Header::read(&mut file).unwrap();
+        let old = Header::read(&mut Cursor::new(&original)).unwrap();
+        let ifd0 = header.read_ifd(&mut file, header.ifd0).unwrap();
+        let old_ifd0 = old.read_ifd(&mut Cursor::new(&original), old.ifd0).unwrap();
+        assert_eq!(ifd0.next, old_ifd0.next);
+        assert_eq!(ifd0.entries.len(), old_ifd0.entries.len() + 1);
+        assert!(ifd0.entries.windows(2).all(|w| w[0].tag < w[1].tag));
+        for entry in old_ifd0.entries {
+            assert!(ifd0
+                .entries
+                .iter()
+                .any(|e| e.tag == entry.tag && e.value == entry.value));
+        }
+        // Everything before the appended data is untouched apart from the IFD0 pointer
+        let pointer = header.ifd0_pointer() as usize;
+        let end = pointer + header.offset_size();
+        assert_eq!(file.get_ref()[end..original.len()], original[end..]);
+    }
+}
+
+#[test]
+pub fn tiff_write_xml_bounded() {
+    let mut file = Cursor::new(sample_tiff(ByteOrder::Little, false));
+    let small = DEFAULT_XML.as_bytes().to_vec();
+    let mut large = small.clone();
+    large.extend_from_slice(&[b' '; 500]);
+
+    __tiff_write_xml(&mut file, &small).unwrap();
+    let header = Header::read(&mut file).unwrap();
+    // The copy of IFD0 is reused by later updates
+    let ifd0 = header.ifd0;
+    __tiff_write_xml(&mut file, &large).unwrap();
+    let size = file.get_ref().len();
+    for _ in 0..3 {
+        __tiff_write_xml(&mut file, &small).unwrap();
+        __tiff_write_xml(&mut file, &large).unwrap();
+    }
+    assert_eq!(file.get_ref().len(), size);
+    assert_eq!(__tiff_xml(&mut file).unwrap(), large);
+    assert_eq!(Header::read(&mut file).unwrap().ifd0, ifd0);
+
+    // A packet which isn't last in the file is only appended once
+    file.get_mut().extend_from_slice(b"trailing data");
+    let mut larger = large.clone();
+    larger.extend_from_slice(&[b' '; 100]);
+    __tiff_write_xml(&mut file, &larger).unwrap();
+    let size = file.get_ref().len();
+    __tiff_write_xml(&mut file, &larger).unwrap();
+    __tiff_write_xml(&mut file, &small).unwrap();
+    assert_eq!(file.get_ref().len(), size);
+}
+
+#[test]
+#[cfg(feature = "tiff")]
+pub fn tiff_update() {
+    let dir = std::env::temp_dir().join("xmp_tiff_update");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("file.tif");
+    std::fs::write(&path, sample_tiff(ByteOrder::Big, false)).unwrap();
+
+    let u = UpdateResults {
+        stars: Some(3),
+        colors: Some(String::from("Yellow")),
+        ..Default::default()
+    };
+    u.update(&path).unwrap();
+    let r = OptionalResults::load(&path).unwrap();
+    assert_eq!(r.stars, Some(3));
+    assert_eq!(r.colors.as_deref(), Some("Yellow"));
+
+    // The shorter packet is read back from the padded space of the first one
+    let size = std::fs::metadata(&path).unwrap().len();
+    let u = UpdateResults {
+        stars: Some(1),
+        ..Default::default()
+    };
+    u.update(&path).unwrap();
+    let r = OptionalResults::load(&path).unwrap();
+    assert_eq!(r.stars, Some(1));
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), size);
+}
+
+#[test]
+#[cfg(feature = "tiff")]
+pub fn dng_embed() {
+    let dir = std::env::temp_dir().join("xmp_dng_embed");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("file.dng");
+    let original = sample_tiff(ByteOrder::Little, false);
+    std::fs::write(&path, &original).unwrap();
+    std::fs::remove_file(path.with_extension("xmp")).ok();
+
+    let u = UpdateResults {
+        stars: Some(5),
+        ..Default::default()
+    };
+    u.write_to_with_options(&path, UpdateOptions::default().embed_dng(true))
+        .unwrap();
+    assert!(!path.with_extension("xmp").exists());
+
+    let data = std::fs::read(&path).unwrap();
+    let r = OptionalResults::from_reader(Cursor::new(__tiff_xml(&mut Cursor::new(&data)).unwrap()))
+        .unwrap();
+    assert_eq!(r.stars, Some(5));
+    // The strip data and all the IFDs except the header pointer are untouched
+    assert_eq!(data[8..original.len()], original[8..]);
+}
+
+#[test]
+#[cfg(feature = "tiff")]
+pub fn dng_embed_sidecar() {
+    let dir = std::env::temp_dir().join("xmp_dng_embed_sidecar");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("file.dng");
+    std::fs::write(&path, sample_tiff(ByteOrder::Little, false)).unwrap();
+    let old = UpdateResults {
+        stars: Some(1),
+        ..Default::default()
+    };
+    old.create_sidecar(&path, UpdateOptions::default()).unwrap();
+
+    let u = UpdateResults {
+        stars: Some(4),
+        ..Default::default()
+    };
+    u.write_to_with_options(&path, UpdateOptions::default().embed_dng(true))
+        .unwrap();
+    assert_eq!(OptionalResults::load(&path).unwrap().stars, Some(4));
+    assert_eq!(
+        OptionalResults::load_xmp(path.with_extension("xmp"))
+            .unwrap()
+            .stars,
+        Some(4)
+    );
+    let data = std::fs::read(&path).unwrap();
+    let r = OptionalResults::from_reader(Cursor::new(__tiff_xml(&mut Cursor::new(&data)).unwrap()))
+        .unwrap();
+    assert_eq!(r.stars, Some(4));
+}
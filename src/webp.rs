@@ -0,0 +1,232 @@
+use crate::*;
+use std::io::Cursor;
+
+const XMP: [u8; 4] = *b"XMP ";
+const VP8X: [u8; 4] = *b"VP8X";
+const VP8: [u8; 4] = *b"VP8 ";
+const VP8L: [u8; 4] = *b"VP8L";
+
+/// Flags in the first byte of the VP8X payload
+const XMP_FLAG: u8 = 0x04;
+const ALPHA_FLAG: u8 = 0x10;
+
+/// A RIFF chunk borrowed from the file, without the padding byte
+#[derive(Debug, Clone, Copy)]
+struct Chunk<'a> {
+    fourcc: [u8; 4],
+    payload: &'a [u8],
+}
+
+fn chunks(data: &[u8]) -> Result<Vec<Chunk<'_>>, XmpError> {
+    if data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"WEBP" {
+        return Err(XmpErrorKind::InvalidWebP.into());
+    }
+    let mut out = Vec::new();
+    let mut offset = 12;
+    while offset + 8 <= data.len() {
+        let mut fourcc = [0; 4];
+        fourcc.copy_from_slice(&data[offset..offset + 4]);
+        let mut size = [0; 4];
+        size.copy_from_slice(&data[offset + 4..offset + 8]);
+        let size = u32::from_le_bytes(size) as usize;
+        let payload = data
+            .get(offset + 8..offset + 8 + size)
+            .otor(|| XmpErrorKind::InvalidWebP)?;
+        out.push(Chunk { fourcc, payload });
+        // Chunks are padded to an even size
+        offset += 8 + size + size % 2;
+    }
+    Ok(out)
+}
+
+fn encode_chunk(out: &mut Vec<u8>, fourcc: &[u8; 4], payload: &[u8]) -> Result<(), XmpError> {
+    let size = u32::try_from(payload.len()).map_err(|_| XmpErrorKind::InvalidWebP)?;
+    out.extend_from_slice(fourcc);
+    out.extend_from_slice(&size.to_le_bytes());
+    out.extend_from_slice(payload);
+    if payload.len() % 2 == 1 {
+        out.push(0);
+    }
+    Ok(())
+}
+
+/// Builds the VP8X payload for a simple lossy or lossless file from its bitstream
+fn vp8x(chunk: &Chunk) -> Result<[u8; 10], XmpError> {
+    let payload = chunk.payload;
+    let (width, height, alpha) = match chunk.fourcc {
+        // 3 byte frame tag, 3 byte start code and 14 bit dimensions
+        VP8 if payload.len() >= 10 && payload[3..6] == [0x9d, 0x01, 0x2a] => (
+            u32::from(u16::from_le_bytes([payload[6], payload[7]]) & 0x3fff),
+            u32::from(u16::from_le_bytes([payload[8], payload[9]]) & 0x3fff),
+            false,
+        ),
+        // Signature followed by 14 bit width - 1, 14 bit height - 1 and the alpha hint
+        VP8L if payload.len() >= 5 && payload[0] == 0x2f => {
+            let bits = u32::from_le_bytes([payload[1], payload[2], payload[3], payload[4]]);
+            (
+                (bits & 0x3fff) + 1,
+                (bits >> 14 & 0x3fff) + 1,
+                bits >> 28 & 1 == 1,
+            )
+        }
+        _ => return Err(XmpErrorKind::InvalidWebP.into()),
+    };
+    if width == 0 || height == 0 {
+        return Err(XmpErrorKind::InvalidWebP.into());
+    }
+    let mut out = [0; 10];
+    out[0] = if alpha { ALPHA_FLAG } else { 0 };
+    out[4..7].copy_from_slice(&(width - 1).to_le_bytes()[..3]);
+    out[7..10].copy_from_slice(&(height - 1).to_le_bytes()[..3]);
+    Ok(out)
+}
+
+pub(crate) fn __webp_xml(data: &[u8]) -> Result<Vec<u8>, XmpError> {
+    chunks(data)?
+        .into_iter()
+        .find(|chunk| chunk.fourcc == XMP)
+        .map(|chunk| chunk.payload.to_vec())
+        .otor(|| XmpErrorKind::XMPMissing)
+        .map_err(Into::into)
+}
+
+/// Returns `data` with the `XMP ` chunk replaced by `xml`
+///
+/// Simple files are upgraded to the extended format with a VP8X chunk. Every other chunk is
+/// copied as is.
+pub(crate) fn __webp_write_xml(data: &[u8], xml: &[u8]) -> Result<Vec<u8>, XmpError> {
+    let chunks = chunks(data)?;
+    let first = chunks.first().otor(|| XmpErrorKind::InvalidWebP)?;
+
+    let mut body = Vec::with_capacity(data.len() + xml.len() + 18);
+    body.extend_from_slice(b"WEBP");
+    let mut header = if first.fourcc == VP8X {
+        let mut header = [0; 10];
+        header.copy_from_slice(first.payload.get(..10).otor(|| XmpErrorKind::InvalidWebP)?);
+        header
+    } else {
+        vp8x(first)?
+    };
+    header[0] |= XMP_FLAG;
+    encode_chunk(&mut body, &VP8X, &header)?;
+
+    let skip = usize::from(first.fourcc == VP8X);
+    for chunk in chunks[skip..].iter().filter(|chunk| chunk.fourcc != XMP) {
+        encode_chunk(&mut body, &chunk.fourcc, chunk.payload)?;
+    }
+    // The XMP chunk comes after the image data and the EXIF chunk
+    encode_chunk(&mut body, &XMP, xml)?;
+
+    let size = u32::try_from(body.len()).map_err(|_| XmpErrorKind::InvalidWebP)?;
+    let mut out = Vec::with_capacity(body.len() + 8);
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&size.to_le_bytes());
+    out.extend(body);
+    Ok(out)
+}
+
+impl UpdateResults {
+    pub fn update_webp(
+        &self,
+        path: impl AsRef<Path>,
+        options: UpdateOptions,
+    ) -> Result<(), XmpError> {
+        let mut reader = BufReader::new(std::fs::File::open(&path)?);
+        replace_file(&path, |writer| {
+            self.apply_webp(&mut reader, writer, options)
+        })
+    }
+
+    pub fn apply_webp<R: Read, W: Write>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+        options: UpdateOptions,
+    ) -> Result<(), XmpError> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        let xml = __webp_xml(&data).unwrap_or_else(|_e| DEFAULT_XML.as_bytes().to_vec());
+        let xml = self.update_xml(Cursor::new(xml), options)?;
+        writer.write_all(&__webp_write_xml(&data, &xml)?)?;
+        Ok(())
+    }
+}
+
+impl OptionalResults {
+    pub fn load_webp(path: impl AsRef<Path>) -> Result<Self, XmpError> {
+        Self::read_webp(&mut BufReader::new(std::fs::File::open(path)?))
+    }
+
+    pub fn read_webp<R: Read>(reader: &mut R) -> Result<Self, XmpError> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        Self::from_reader(Cursor::new(__webp_xml(&data)?))
+    }
+}
+
+#[cfg(test)]
+fn sample_webp(chunks: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+    let mut body = b"WEBP".to_vec();
+    for (fourcc, payload) in chunks {
+        encode_chunk(&mut body, fourcc, payload).unwrap();
+    }
+    [&b"RIFF"[..], &(body.len() as u32).to_le_bytes(), &body].concat()
+}
+
+#[test]
+pub fn webp_upgrade_simple() {
+    // 64x48 lossy frame
+    let vp8: &[u8] = &[
+        0x30, 0x01, 0x00, 0x9d, 0x01, 0x2a, 0x40, 0x00, 0x30, 0x00, 0xaa,
+    ];
+    // 64x48 lossless frame with the alpha hint set
+    let vp8l: &[u8] = &[0x2f, 0x3f, 0xc0, 0x0b, 0x10, 0xbb];
+
+    for (fourcc, payload, flags) in [(&VP8, vp8, XMP_FLAG), (&VP8L, vp8l, XMP_FLAG | ALPHA_FLAG)] {
+        let data = __webp_write_xml(&sample_webp(&[(fourcc, payload)]), b"xml").unwrap();
+        let chunks = chunks(&data).unwrap();
+        assert_eq!(chunks[0].fourcc, VP8X);
+        assert_eq!(chunks[0].payload, &[flags, 0, 0, 0, 63, 0, 0, 47, 0, 0]);
+        assert_eq!(chunks[1].fourcc, *fourcc);
+        assert_eq!(chunks[1].payload, payload);
+        assert_eq!(__webp_xml(&data).unwrap(), b"xml");
+        assert_eq!(
+            data.len(),
+            8 + u32::from_le_bytes([data[4], data[5], data[6], data[7]]) as usize
+        );
+    }
+}
+
+#[test]
+pub fn webp_update_extended() {
+    let dir = std::env::temp_dir().join("xmp_webp_update_extended");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("file.webp");
+    let original: [(&[u8; 4], &[u8]); 5] = [
+        (&VP8X, &[0x2a, 0, 0, 0, 63, 0, 0, 47, 0, 0]),
+        (b"ICCP", b"icc profile"),
+        (b"ANIM", &[0, 0, 0, 0, 0, 0]),
+        (b"ANMF", b"frame"),
+        (b"EXIF", b"exif data"),
+    ];
+    std::fs::write(&path, sample_webp(&original)).unwrap();
+
+    for stars in [2, 3] {
+        let u = UpdateResults {
+            stars: Some(stars),
+            ..Default::default()
+        };
+        u.update(&path).unwrap();
+        assert_eq!(OptionalResults::load(&path).unwrap().stars, Some(stars));
+    }
+
+    let data = std::fs::read(&path).unwrap();
+    let chunks = chunks(&data).unwrap();
+    assert_eq!(chunks.len(), original.len() + 1);
+    assert_eq!(chunks[0].payload[0], 0x2a | XMP_FLAG);
+    for (chunk, (fourcc, payload)) in chunks[1..].iter().zip(&original[1..]) {
+        assert_eq!(&chunk.fourcc, *fourcc);
+        assert_eq!(&chunk.payload, payload);
+    }
+    assert_eq!(chunks[original.len()].fourcc, XMP);
+}
@@ -1,4 +1,5 @@
 use crate::*;
+use std::io::Cursor;
 // use std::io::BufReader;
 
 impl UpdateResults {
@@ -18,6 +19,45 @@ impl UpdateResults {
 
         Ok(())
     }
+
+    pub fn apply_xmp<R: Read + Seek, W: Write>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+        options: UpdateOptions,
+    ) -> Result<(), XmpError> {
+        let xml = match self.update_xml(BufReader::new(reader), options.clone()) {
+            Ok(xml) => xml,
+            // An unreadable packet is replaced, the update still has to end up in its replacement
+            Err(_e) => self.update_xml(Cursor::new(DEFAULT_XML.as_bytes()), options)?,
+        };
+        writer.write_all(xml.as_slice())?;
+        Ok(())
+    }
+
+    /// Creates a new sidecar next to the raw file from [`DEFAULT_XML`] and applies the update to it
+    pub fn create_sidecar(
+        &self,
+        raw: impl AsRef<Path>,
+        options: UpdateOptions,
+    ) -> Result<(), XmpError> {
+        let sidecar = UpdateResults {
+            sidecar_for_extension: raw
+                .as_ref()
+                .extension()
+                .and_then(OsStr::to_str)
+                .map(ToOwned::to_owned),
+            ..Default::default()
+        };
+        let xml = sidecar.update_xml(Cursor::new(DEFAULT_XML.as_bytes()), options.clone())?;
+        let xml = self.update_xml(Cursor::new(xml), options)?;
+
+        let mut bfw = BufWriter::new(std::fs::File::create(raw.as_ref().with_extension("xmp"))?);
+        bfw.write_all(xml.as_slice())?;
+        bfw.flush()?;
+
+        Ok(())
+    }
 }
 
 impl OptionalResults {
@@ -26,6 +66,64 @@ impl OptionalResults {
         // let data = std::fs::read(path)?;
         // Self::from_reader(data.as_slice())
     }
+
+    pub fn read_xmp<R: Read + Seek>(reader: &mut R) -> Result<Self, XmpError> {
+        Self::from_reader(BufReader::new(reader))
+    }
+}
+
+/// Sidecars are the packet itself
+pub(crate) struct SidecarContainer;
+
+impl container::XmpContainer for SidecarContainer {
+    fn name(&self) -> &str {
+        "xmp"
+    }
+
+    fn detect(&self, path: &Path, magic: &[u8]) -> bool {
+        ImageType::from_sniffed(path, magic) == ImageType::Xmp
+    }
+
+    fn extract(&self, reader: &mut dyn container::ReadSeek) -> Result<Vec<u8>, XmpError> {
+        let mut xml = Vec::new();
+        reader.read_to_end(&mut xml)?;
+        Ok(xml)
+    }
+
+    fn replace(
+        &self,
+        _reader: &mut dyn container::ReadSeek,
+        writer: &mut dyn Write,
+        xml: &[u8],
+        _options: &UpdateOptions,
+    ) -> Result<(), XmpError> {
+        writer.write_all(xml)?;
+        Ok(())
+    }
+
+    fn load(&self, path: &Path) -> Result<OptionalResults, XmpError> {
+        OptionalResults::load_xmp(path)
+    }
+
+    /// Falls back to [`DEFAULT_XML`] when the update fails instead of the existing sidecar
+    fn apply(
+        &self,
+        results: &UpdateResults,
+        mut reader: &mut dyn container::ReadSeek,
+        mut writer: &mut dyn Write,
+        options: UpdateOptions,
+    ) -> Result<(), XmpError> {
+        results.apply_xmp(&mut reader, &mut writer, options)
+    }
+
+    fn update(
+        &self,
+        results: &UpdateResults,
+        path: &Path,
+        options: UpdateOptions,
+    ) -> Result<(), XmpError> {
+        results.update_xmp(path, options)
+    }
 }
 
 #[test]
@@ -55,6 +153,7 @@ pub fn set_subjects() {
         UpdateOptions {
             indent: Some((b' ', 4)),
             overwrite: false,
+            ..Default::default()
         },
     )
     .unwrap();
@@ -73,3 +172,162 @@ pub fn missing_namespace() {
     let r = OptionalResults::load("assets/missing_ns.xmp").unwrap();
     assert_eq!(e, r);
 }
+
+#[test]
+pub fn create_sidecar() {
+    let dir = std::env::temp_dir().join("xmp_create_sidecar");
+    std::fs::create_dir_all(&dir).unwrap();
+    let raw = dir.join("file.NEF");
+    std::fs::write(&raw, []).unwrap();
+    std::fs::remove_file(raw.with_extension("xmp")).ok();
+
+    let x = UpdateResults {
+        stars: Some(4),
+        ..Default::default()
+    };
+    let strict = UpdateOptions::default().create_sidecar(false);
+    assert!(x.write_to_with_options(&raw, strict).is_err());
+
+    x.update(&raw).unwrap();
+    let r = OptionalResults::load(&raw).unwrap();
+    assert_eq!(r.stars, Some(4));
+    assert_eq!(r.sidecar_for_extension.as_deref(), Some("NEF"));
+}
+
+/// Namespaces split over several descriptions like exiftool and older Photoshop versions do
+#[cfg(test)]
+const SPLIT_XML: &str = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about="" xmlns:tiff="http://ns.adobe.com/tiff/1.0/" tiff:Orientation="6"/>
+  <rdf:Description rdf:about="" xmlns:xmp="http://ns.adobe.com/xap/1.0/" xmp:Rating="2" xmp:Label="Red"/>
+  <rdf:Description rdf:about="" xmlns:dc="http://purl.org/dc/elements/1.1/">
+   <dc:subject><rdf:Bag><rdf:li>Travel</rdf:li></rdf:Bag></dc:subject>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>"#;
+
+#[test]
+pub fn split_descriptions() {
+    use std::io::Cursor;
+
+    let r = OptionalResults::from_reader(Cursor::new(SPLIT_XML)).unwrap();
+    assert_eq!(r.stars, Some(2));
+    assert_eq!(r.colors.as_deref(), Some("Red"));
+    assert_eq!(r.orientation, Some(6));
+    assert_eq!(r.subjects, Some(vec![String::from("Travel")]));
+
+    let u = UpdateResults {
+        stars: Some(5),
+        colors: Some(String::from("Green")),
+        subjects: Some(vec![String::from("Travel")]),
+        ..Default::default()
+    };
+    let xml = u
+        .update_xml(Cursor::new(SPLIT_XML), UpdateOptions::default())
+        .unwrap();
+    let text = String::from_utf8(xml.clone()).unwrap();
+    assert_eq!(text.matches("Rating").count(), 1);
+    assert_eq!(text.matches("Label").count(), 1);
+    assert_eq!(text.matches("subject").count(), 2);
+    let r = OptionalResults::from_reader(Cursor::new(xml)).unwrap();
+    assert_eq!(r.stars, Some(5));
+    assert_eq!(r.colors.as_deref(), Some("Green"));
+
+    let merge = UpdateOptions::default().merge_descriptions(true);
+    let xml = u.update_xml(Cursor::new(SPLIT_XML), merge).unwrap();
+    let element = try_load_element(Cursor::new(&xml)).unwrap();
+    assert_eq!(try_get_descriptions(&element).unwrap().len(), 1);
+    let r = OptionalResults::from_reader(Cursor::new(xml)).unwrap();
+    assert_eq!(r.stars, Some(5));
+    assert_eq!(r.orientation, Some(6));
+    assert_eq!(r.subjects, Some(vec![String::from("Travel")]));
+}
+
+/// The xmp namespace bound to the prefix older Adobe software uses, declared on `rdf:RDF`
+#[cfg(test)]
+const XAP_XML: &str = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#" xmlns:xap="http://ns.adobe.com/xap/1.0/">
+  <rdf:Description rdf:about="" xmlns:ns1="http://ns.adobe.com/photoshop/1.0/" xap:Rating="4" ns1:SidecarForExtension="CR2"/>
+ </rdf:RDF>
+</x:xmpmeta>"#;
+
+#[test]
+pub fn foreign_prefixes() {
+    use std::io::Cursor;
+
+    let r = OptionalResults::from_reader(Cursor::new(XAP_XML)).unwrap();
+    assert_eq!(r.stars, Some(4));
+    assert_eq!(r.sidecar_for_extension.as_deref(), Some("CR2"));
+
+    let u = UpdateResults {
+        stars: Some(1),
+        colors: Some(String::from("Yellow")),
+        sidecar_for_extension: Some(String::from("NEF")),
+        ..Default::default()
+    };
+    let xml = u
+        .update_xml(Cursor::new(XAP_XML), UpdateOptions::default())
+        .unwrap();
+    let text = String::from_utf8(xml.clone()).unwrap();
+    assert!(text.contains(r#"xap:Rating="1""#));
+    assert!(text.contains(r#"xap:Label="Yellow""#));
+    assert!(text.contains(r#"ns1:SidecarForExtension="NEF""#));
+    assert!(!text.contains("xmlns:xmp="));
+    assert_eq!(text.matches("Rating").count(), 1);
+
+    let r = OptionalResults::from_reader(Cursor::new(xml)).unwrap();
+    assert_eq!(r.stars, Some(1));
+    assert_eq!(r.colors.as_deref(), Some("Yellow"));
+}
+
+/// Properties in the element form, in a second description
+#[cfg(test)]
+const ELEMENT_XML: &str = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about="" xmlns:tiff="http://ns.adobe.com/tiff/1.0/" tiff:Orientation="1"/>
+  <rdf:Description rdf:about="" xmlns:xap="http://ns.adobe.com/xap/1.0/">
+   <xap:Rating>2</xap:Rating>
+   <xap:Label>Red</xap:Label>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>"#;
+
+#[test]
+pub fn element_properties() {
+    use std::io::Cursor;
+
+    let u = UpdateResults {
+        stars: Some(5),
+        colors: Some(String::from("Green")),
+        ..Default::default()
+    };
+    let xml = u
+        .update_xml(Cursor::new(ELEMENT_XML), UpdateOptions::default())
+        .unwrap();
+    let text = String::from_utf8(xml.clone()).unwrap();
+    assert!(text.contains(r#"xap:Rating="5""#));
+    assert!(!text.contains("<xap:Rating>"));
+    assert_eq!(text.matches("Label").count(), 1);
+    let element = try_load_element(Cursor::new(&xml)).unwrap();
+    let descriptions = try_get_descriptions(&element).unwrap();
+    assert!(!descriptions[0]
+        .attrs()
+        .any(|(key, _)| key.ends_with("Rating")));
+
+    let r = OptionalResults::from_reader(Cursor::new(xml)).unwrap();
+    assert_eq!(r.stars, Some(5));
+    assert_eq!(r.colors.as_deref(), Some("Green"));
+}
+
+#[test]
+pub fn xmp_apply_to_garbage() {
+    let u = UpdateResults {
+        stars: Some(2),
+        colors: Some(String::from("Blue")),
+        ..Default::default()
+    };
+    let data = u.apply_to_bytes(ImageType::Xmp, b"not a packet").unwrap();
+    let r = OptionalResults::from_bytes(ImageType::Xmp, &data).unwrap();
+    assert_eq!(r.stars, Some(2));
+    assert_eq!(r.colors.as_deref(), Some("Blue"));
+}